@@ -0,0 +1,71 @@
+//! Compares `BmsParser::scan_header` against a full `BmsParser::parse` on a large chart.
+//!
+//! Run with `cargo bench --bench scan_header`.
+use bms_rs::bms::parser::BmsParser;
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{Seek, SeekFrom},
+    time::{Duration, Instant},
+};
+
+const MEASURES: u32 = 999;
+const ITERATIONS: u32 = 10;
+
+/// Builds a chart with dense notes on every lane of both sides.
+fn large_chart() -> String {
+    let mut chart = String::from("#TITLE Benchmark\n#ARTIST bms-rs\n#PLAYER 3\n#BPM 150\n");
+    for key in 1..36 * 36 {
+        writeln!(chart, "#WAV{:02} {}.wav", to_base36(key), key).unwrap();
+    }
+    for measure in 0..MEASURES {
+        for channel in [
+            "11", "12", "13", "14", "15", "18", "19", "21", "22", "23", "24", "25",
+        ] {
+            let data: String = (0..32)
+                .map(|slot| to_base36(1 + (measure as usize * 32 + slot) % 1295))
+                .collect();
+            writeln!(chart, "#{:03}{}:{}", measure, channel, data).unwrap();
+        }
+        writeln!(chart, "#{:03}01:{}", measure, "0A".repeat(16)).unwrap();
+    }
+    chart
+}
+
+fn to_base36(key: usize) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let digits = [DIGITS[key / 36], DIGITS[key % 36]];
+    String::from_utf8(digits.to_vec()).unwrap()
+}
+
+/// Runs `f` on the file `ITERATIONS` times and returns the average duration.
+fn time(file: &mut File, mut f: impl FnMut(&mut File)) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        file.seek(SeekFrom::Start(0)).unwrap();
+        f(file);
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let path = std::env::temp_dir().join("bms-rs-scan-header-bench.bms");
+    fs::write(&path, large_chart()).unwrap();
+    let mut file = File::open(&path).unwrap();
+    let parser = BmsParser::new();
+
+    let parse = time(&mut file, |file| {
+        parser.parse(file).unwrap();
+    });
+    let scan = time(&mut file, |file| {
+        parser.scan_header(file).unwrap();
+    });
+    println!("parse:       {:>10.2?} per chart", parse);
+    println!("scan_header: {:>10.2?} per chart", scan);
+    println!(
+        "scan_header is {:.1}x faster",
+        parse.as_secs_f64() / scan.as_secs_f64()
+    );
+
+    fs::remove_file(&path).unwrap();
+}
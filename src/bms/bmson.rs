@@ -0,0 +1,881 @@
+/// Reading and writing of bmson charts, the JSON-based member of the BMS family.
+///
+/// bmson places everything in "pulses", a fixed number of which (`info.resolution`) make up a
+/// beat. Charts are converted into the same `BMS` model that `BmsParser` builds, so both formats
+/// can be handled the same way: pulses become measure fractions using the bar `lines`, each
+/// sound channel becomes a keysound, and lanes become BMS channels. `BmsonWriter` does the
+/// reverse, so BMS charts can be converted into bmson.
+use crate::bms::{
+    error::{ParseError, ParseErrorKind, WarningKind},
+    format::{BmsBuilder, BMS},
+    header::{JudgeRank, PlayerMode},
+    timeline::TimelineEvent,
+    Alphanumeric, Base, BgaLayer, LaneLayout, ObjType, Object, PlayerSide,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// A bmson chart, as stored in the file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Bmson {
+    pub version: String,
+    pub info: BmsonInfo,
+    /// The bar lines, which mark where each measure starts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<Vec<BarLine>>,
+    pub bpm_events: Vec<BpmEvent>,
+    pub stop_events: Vec<StopEvent>,
+    pub sound_channels: Vec<SoundChannel>,
+    pub bga: Bga,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BmsonInfo {
+    pub title: String,
+    pub subtitle: String,
+    pub artist: String,
+    pub subartists: Vec<String>,
+    pub genre: String,
+    /// The lane layout, such as `beat-7k` or `beat-14k`.
+    pub mode_hint: String,
+    pub chart_name: String,
+    pub level: u32,
+    pub init_bpm: f64,
+    /// The judge window as a percentage of the normal window.
+    pub judge_rank: f64,
+    /// The total gauge increase over the chart, as a percentage of the default.
+    pub total: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub back_image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eyecatch_image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner_image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_music: Option<String>,
+    /// The number of pulses in a beat.
+    pub resolution: u64,
+}
+
+impl Default for BmsonInfo {
+    fn default() -> Self {
+        // The defaults from the bmson 1.0 specification
+        BmsonInfo {
+            title: String::new(),
+            subtitle: String::new(),
+            artist: String::new(),
+            subartists: Vec::new(),
+            genre: String::new(),
+            mode_hint: "beat-7k".to_string(),
+            chart_name: String::new(),
+            level: 0,
+            init_bpm: 0.0,
+            judge_rank: 100.0,
+            total: 100.0,
+            back_image: None,
+            eyecatch_image: None,
+            banner_image: None,
+            preview_music: None,
+            resolution: 240,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BarLine {
+    pub y: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BpmEvent {
+    pub y: u64,
+    pub bpm: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StopEvent {
+    pub y: u64,
+    /// The length of the stop, in pulses.
+    pub duration: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SoundChannel {
+    /// The sound file.
+    pub name: String,
+    pub notes: Vec<Note>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Note {
+    /// The lane, or 0 for background sounds.
+    #[serde(default, deserialize_with = "null_as_zero")]
+    pub x: u32,
+    pub y: u64,
+    /// The length of a long note in pulses, or 0 for a normal note.
+    #[serde(default)]
+    pub l: u64,
+    /// Whether the sound carries on from the previous note of the channel instead of restarting.
+    #[serde(default)]
+    pub c: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Bga {
+    pub bga_header: Vec<BgaHeader>,
+    pub bga_events: Vec<BgaEvent>,
+    pub layer_events: Vec<BgaEvent>,
+    pub poor_events: Vec<BgaEvent>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BgaHeader {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BgaEvent {
+    pub y: u64,
+    pub id: u64,
+}
+
+fn null_as_zero<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    Ok(Option::<u32>::deserialize(deserializer)?.unwrap_or(0))
+}
+
+/// Converts pulses into measure fractions, using the bar lines.
+struct Measures {
+    // The pulse where each measure starts
+    starts: Vec<u64>,
+    // The length of the measures after the last bar line, a 4/4 measure
+    default_length: u64,
+}
+
+impl Measures {
+    fn new(lines: &[BarLine], resolution: u64) -> Measures {
+        let mut starts: Vec<u64> = std::iter::once(0)
+            .chain(lines.iter().map(|l| l.y))
+            .collect();
+        starts.sort_unstable();
+        starts.dedup();
+        Measures {
+            starts,
+            default_length: 4 * resolution,
+        }
+    }
+
+    fn measure(&self, y: u64) -> f32 {
+        let index = self.starts.partition_point(|&start| start <= y) - 1;
+        let start = self.starts[index];
+        let measure = match self.starts.get(index + 1) {
+            Some(end) => index as f64 + (y - start) as f64 / (end - start) as f64,
+            None => index as f64 + (y - start) as f64 / self.default_length as f64,
+        };
+        measure as f32
+    }
+
+    /// Returns the length of every measure that isn't 4/4, as a multiple of a 4/4 measure.
+    fn lengths(&self) -> impl Iterator<Item = (u32, f32)> + '_ {
+        let default_length = self.default_length;
+        self.starts
+            .windows(2)
+            .enumerate()
+            .map(move |(measure, bounds)| {
+                let length = (bounds[1] - bounds[0]) as f64 / default_length as f64;
+                (measure as u32, length as f32)
+            })
+            .filter(|&(_, length)| length != 1_f32)
+    }
+}
+
+#[derive(Default)]
+pub struct BmsonParser {
+    // Where the chart was loaded from, used to resolve its assets.
+    path: Option<PathBuf>,
+}
+
+impl BmsonParser {
+    pub fn new() -> BmsonParser {
+        BmsonParser { path: None }
+    }
+
+    /// Sets the path the chart was loaded from. It is only used to resolve the chart's assets
+    /// with `BMS::resolve_asset`.
+    pub fn with_path<P: AsRef<Path>>(&mut self, path: P) -> &Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Parses a bmson chart from any reader.
+    pub fn parse_reader<R: Read>(&self, reader: R) -> Result<BMS, ParseError> {
+        self.parse_bmson(serde_json::from_reader(reader).map_err(json_error)?)
+    }
+
+    /// Parses a bmson chart from its raw bytes, which must be UTF-8.
+    pub fn parse_bytes(&self, contents: &[u8]) -> Result<BMS, ParseError> {
+        self.parse_bmson(serde_json::from_slice(contents).map_err(json_error)?)
+    }
+
+    pub fn parse_str(&self, text: &str) -> Result<BMS, ParseError> {
+        self.parse_bmson(serde_json::from_str(text).map_err(json_error)?)
+    }
+
+    /// Converts a deserialized bmson chart into the BMS model.
+    pub fn parse_bmson(&self, bmson: Bmson) -> Result<BMS, ParseError> {
+        let info = &bmson.info;
+        if info.resolution == 0 {
+            return Err(ParseError::new(
+                0,
+                "",
+                ParseErrorKind::InvalidBmson("resolution must be positive".to_string()),
+            ));
+        }
+
+        let mut bms_builder = BmsBuilder::new();
+        if let Some(path) = &self.path {
+            bms_builder.with_path(path);
+        }
+        for (header, value) in [
+            ("TITLE", &info.title),
+            ("SUBTITLE", &info.subtitle),
+            ("ARTIST", &info.artist),
+            ("SUBARTIST", &info.subartists.join(", ")),
+            ("GENRE", &info.genre),
+        ] {
+            if !value.is_empty() {
+                bms_builder.with_metadata(header.to_string(), value.to_string());
+            }
+        }
+        for (header, value) in [
+            ("STAGEFILE", &info.eyecatch_image),
+            ("BANNER", &info.banner_image),
+            ("BACKBMP", &info.back_image),
+            ("PREVIEW", &info.preview_music),
+        ] {
+            if let Some(value) = value {
+                bms_builder.with_metadata(header.to_string(), value.to_string());
+            }
+        }
+        // Lanes are numbered the same way as `LaneLayout` numbers them
+        let layout = if info.mode_hint.starts_with("popn-") {
+            LaneLayout::PopN
+        } else {
+            LaneLayout::Beat
+        };
+        bms_builder.with_layout(layout);
+        match info.mode_hint.as_str() {
+            "beat-5k" | "beat-7k" | "popn-5k" | "popn-9k" => {
+                bms_builder.with_metadata("PLAYER".to_string(), "1".to_string());
+            }
+            "beat-10k" | "beat-14k" => {
+                bms_builder.with_metadata("PLAYER".to_string(), "3".to_string());
+            }
+            _ => {}
+        }
+        bms_builder.with_metadata("PLAYLEVEL".to_string(), info.level.to_string());
+        bms_builder.with_metadata("DEFEXRANK".to_string(), info.judge_rank.to_string());
+        bms_builder.with_metadata("TOTAL".to_string(), info.total.to_string());
+
+        // Timing
+        let resolution = info.resolution;
+        let measures = Measures::new(bmson.lines.as_deref().unwrap_or(&[]), resolution);
+        bms_builder
+            .timeline_builder
+            .with_base_bpm(check_bpm(info.init_bpm)?);
+        for (measure, length) in measures.lengths() {
+            if measure < 1000 {
+                bms_builder
+                    .timeline_builder
+                    .with_measure_len(measure, length);
+            }
+        }
+        for event in bmson.bpm_events.iter() {
+            bms_builder.timeline_builder.with_event(TimelineEvent::BPM {
+                measure: measures.measure(event.y),
+                bpm: check_bpm(event.bpm)?,
+            });
+        }
+        for event in bmson.stop_events.iter() {
+            // STOP durations are in 192nds of a 4/4 measure
+            bms_builder
+                .timeline_builder
+                .with_event(TimelineEvent::STOP {
+                    measure: measures.measure(event.y),
+                    duration: (event.duration as f64 * 48.0 / resolution as f64) as f32,
+                });
+        }
+
+        // Every sound channel gets its own keysound
+        if bmson.sound_channels.len() >= 36 * 36 {
+            bms_builder.with_base(Base::Base62);
+        }
+        for (index, sound_channel) in bmson.sound_channels.iter().enumerate() {
+            let key = Alphanumeric::from_int(index + 1);
+            bms_builder.with_keysound(key, sound_channel.name.clone());
+            for note in sound_channel.notes.iter() {
+                let measure = measures.measure(note.y);
+                let (channel, objtype) = match (note.x, layout.channel(note.x)) {
+                    (0, _) => (1, ObjType::Auto(key)),
+                    (_, Some(channel)) if note.l > 0 => (
+                        // The long-note channel for the lane
+                        channel + 4 * 36,
+                        ObjType::LongNote {
+                            key,
+                            end_measure: measures.measure(note.y + note.l),
+                            end_time: 0,
+                        },
+                    ),
+                    (_, Some(channel)) => (channel, ObjType::Note(key)),
+                    (x, None) => {
+                        bms_builder.warn(WarningKind::UnsupportedLane(x));
+                        continue;
+                    }
+                };
+                bms_builder.add_object(Object {
+                    measure,
+                    channel,
+                    objtype,
+                    side: PlayerSide::from_channel(channel),
+                    continuation: note.c,
+                    ..Default::default()
+                });
+            }
+        }
+
+        // BGA ids can be any number, so they're given keys in order
+        let mut bga_keys: HashMap<u64, Alphanumeric> = HashMap::new();
+        for (index, header) in bmson.bga.bga_header.iter().enumerate() {
+            let key = Alphanumeric::from_int(index + 1);
+            bga_keys.insert(header.id, key);
+            bms_builder.with_bga_layer(key, header.name.clone());
+        }
+        for (events, layer, channel) in [
+            (&bmson.bga.bga_events, BgaLayer::Base, 4),
+            (&bmson.bga.layer_events, BgaLayer::Layer, 7),
+            (&bmson.bga.poor_events, BgaLayer::Poor, 6),
+        ] {
+            for event in events.iter() {
+                if let Some(&key) = bga_keys.get(&event.id) {
+                    bms_builder.add_object(Object {
+                        measure: measures.measure(event.y),
+                        channel,
+                        objtype: ObjType::BGA { layer, key },
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        Ok(bms_builder.build())
+    }
+}
+
+fn json_error(e: serde_json::Error) -> ParseError {
+    ParseError::new(e.line(), "", ParseErrorKind::InvalidBmson(e.to_string()))
+}
+
+/// Rejects the BPMs a chart can't be played at, including a missing `init_bpm`.
+fn check_bpm(bpm: f64) -> Result<f32, ParseError> {
+    let value = bpm as f32;
+    if value.is_finite() && value > 0.0 {
+        Ok(value)
+    } else {
+        Err(ParseError::new(
+            0,
+            "",
+            ParseErrorKind::InvalidBpm(bpm.to_string()),
+        ))
+    }
+}
+
+/// Converts charts into bmson.
+pub struct BmsonWriter {
+    // The number of pulses in a beat
+    resolution: u64,
+}
+
+impl Default for BmsonWriter {
+    fn default() -> Self {
+        BmsonWriter::new()
+    }
+}
+
+impl BmsonWriter {
+    pub fn new() -> BmsonWriter {
+        BmsonWriter { resolution: 240 }
+    }
+
+    /// Sets the number of pulses in a beat. Positions are rounded to the nearest pulse, so the
+    /// resolution should be divisible by the chart's finest subdivision of a beat. The default
+    /// of 240 is enough for most charts.
+    pub fn with_resolution(&mut self, resolution: u64) -> &Self {
+        self.resolution = resolution.max(1);
+        self
+    }
+
+    /// Writes the chart as bmson text.
+    pub fn write(&self, bms: &BMS) -> String {
+        serde_json::to_string_pretty(&self.to_bmson(bms)).expect("bmson is always serializable")
+    }
+
+    /// Converts the chart into bmson. Objects that bmson can't represent, such as invisible
+    /// notes, landmines and foot pedal notes, are left out, as are scroll and speed changes.
+    pub fn to_bmson(&self, bms: &BMS) -> Bmson {
+        let resolution = self.resolution;
+        let timing = &bms.timing;
+        let last_measure = bms
+            .objects
+            .iter()
+            .map(|o| match o.objtype {
+                ObjType::LongNote { end_measure, .. } => end_measure,
+                _ => o.measure,
+            })
+            .chain(timing.events.iter().map(|e| match *e {
+                TimelineEvent::BPM { measure, .. }
+                | TimelineEvent::STOP { measure, .. }
+                | TimelineEvent::Scroll { measure, .. }
+                | TimelineEvent::Speed { measure, .. } => measure,
+            }))
+            .fold(0_f32, f32::max) as u32;
+
+        // The pulse where each measure starts, up to the end of the last measure
+        let measure_pulses = |measure: u32| {
+            let length = timing.measure_lengths.get(&measure).unwrap_or(&1_f32);
+            (*length as f64 * 4.0 * resolution as f64).round() as u64
+        };
+        let mut starts = vec![0];
+        for measure in 0..=last_measure {
+            starts.push(starts[measure as usize] + measure_pulses(measure));
+        }
+        let pulse = |measure: f32| {
+            let index = measure.floor() as usize;
+            let fraction = (measure - measure.floor()) as f64;
+            starts[index] + (fraction * measure_pulses(index as u32) as f64).round() as u64
+        };
+
+        // Every keysound becomes a sound channel, even if it's never used
+        let mut notes: BTreeMap<usize, Vec<Note>> = bms
+            .keysounds
+            .keys()
+            .map(|key| (key.key, Vec::new()))
+            .collect();
+        let mut bga_events = Bga::default();
+        for object in bms.objects.iter() {
+            let y = pulse(object.measure);
+            let (key, x, l) = match object.objtype {
+                ObjType::Auto(key) => (key, 0, 0),
+                ObjType::Note(key) => match bms.layout.lane(object.channel) {
+                    Some(x) => (key, x, 0),
+                    None => continue,
+                },
+                ObjType::LongNote {
+                    key, end_measure, ..
+                } => match bms.layout.lane(object.channel) {
+                    Some(x) => (key, x, pulse(end_measure) - y),
+                    None => continue,
+                },
+                ObjType::BGA { layer, key } => {
+                    let event = BgaEvent {
+                        y,
+                        id: key.key as u64,
+                    };
+                    match layer {
+                        BgaLayer::Base => bga_events.bga_events.push(event),
+                        BgaLayer::Layer | BgaLayer::Layer2 => bga_events.layer_events.push(event),
+                        BgaLayer::Poor => bga_events.poor_events.push(event),
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            notes.entry(key.key).or_default().push(Note {
+                x,
+                y,
+                l,
+                c: object.continuation,
+            });
+        }
+        let sound_channels: Vec<SoundChannel> = notes
+            .into_iter()
+            .map(|(key, notes)| SoundChannel {
+                name: bms
+                    .keysounds
+                    .get(&Alphanumeric::from_int(key))
+                    .cloned()
+                    .unwrap_or_default(),
+                notes,
+            })
+            .collect();
+        let mut bga_header: Vec<BgaHeader> = bms
+            .bga_layers
+            .iter()
+            .map(|(key, name)| BgaHeader {
+                id: key.key as u64,
+                name: name.clone(),
+            })
+            .collect();
+        bga_header.sort_by_key(|header| header.id);
+
+        let mut bpm_events = Vec::new();
+        let mut stop_events = Vec::new();
+        for event in timing.events.iter() {
+            match *event {
+                TimelineEvent::BPM { measure, bpm } => bpm_events.push(BpmEvent {
+                    y: pulse(measure),
+                    bpm: bpm as f64,
+                }),
+                // STOP durations are in 192nds of a 4/4 measure
+                TimelineEvent::STOP { measure, duration } => stop_events.push(StopEvent {
+                    y: pulse(measure),
+                    duration: (duration as f64 * resolution as f64 / 48.0).round() as u64,
+                }),
+                _ => {}
+            }
+        }
+        bpm_events.sort_by_key(|event| event.y);
+        stop_events.sort_by_key(|event| event.y);
+
+        let lanes = sound_channels
+            .iter()
+            .flat_map(|c| c.notes.iter().map(|n| n.x));
+        let double = bms.header.player == Some(PlayerMode::Double) || lanes.clone().any(|x| x > 8);
+        // Lanes 6-7 and 14-15 are keys 6 and 7
+        let seven_keys = lanes.clone().any(|x| x % 8 == 6 || x % 8 == 7);
+        let mode_hint = match (double, seven_keys) {
+            _ if bms.layout == LaneLayout::PopN => {
+                if lanes.clone().any(|x| x > 5) {
+                    "popn-9k"
+                } else {
+                    "popn-5k"
+                }
+            }
+            (false, false) => "beat-5k",
+            (false, true) => "beat-7k",
+            (true, false) => "beat-10k",
+            (true, true) => "beat-14k",
+        };
+
+        let header = &bms.header;
+        let text = |value: &Option<String>| value.clone().unwrap_or_default();
+        Bmson {
+            version: "1.0.0".to_string(),
+            info: BmsonInfo {
+                title: text(&header.title),
+                subtitle: text(&header.subtitle),
+                artist: text(&header.artist),
+                subartists: header.subartist.iter().cloned().collect(),
+                genre: text(&header.genre),
+                mode_hint: mode_hint.to_string(),
+                chart_name: String::new(),
+                level: header.playlevel.unwrap_or(0),
+                init_bpm: timing.base_bpm as f64,
+                judge_rank: header
+                    .exrank
+                    .or_else(|| header.rank.map(rank_percentage))
+                    .unwrap_or(100_f32) as f64,
+                total: header.total.unwrap_or(100_f32) as f64,
+                back_image: header.backbmp.clone(),
+                eyecatch_image: header.stagefile.clone(),
+                banner_image: header.banner.clone(),
+                preview_music: header.preview.clone(),
+                resolution,
+            },
+            lines: Some(starts.into_iter().map(|y| BarLine { y }).collect()),
+            bpm_events,
+            stop_events,
+            sound_channels,
+            bga: Bga {
+                bga_header,
+                ..bga_events
+            },
+        }
+    }
+}
+
+/// Returns the judge window of a `#RANK` as a percentage of the normal window, the unit of
+/// `#DEFEXRANK` and bmson's `judge_rank`.
+fn rank_percentage(rank: JudgeRank) -> f32 {
+    match rank {
+        JudgeRank::VeryHard => 25_f32,
+        JudgeRank::Hard => 50_f32,
+        JudgeRank::Normal => 100_f32,
+        JudgeRank::Easy => 150_f32,
+        JudgeRank::VeryEasy => 200_f32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bms::parser::BmsParser;
+
+    const CHART: &str = r#"{
+        "version": "1.0.0",
+        "info": {
+            "title": "Bmson Test",
+            "artist": "someone",
+            "subartists": ["obj: a", "bga: b"],
+            "genre": "Test",
+            "mode_hint": "beat-7k",
+            "level": 7,
+            "init_bpm": 120,
+            "judge_rank": 100,
+            "total": 300,
+            "resolution": 240
+        },
+        "lines": [{"y": 0}, {"y": 960}, {"y": 1680}, {"y": 2640}],
+        "bpm_events": [{"y": 960, "bpm": 240}],
+        "stop_events": [{"y": 1680, "duration": 240}],
+        "sound_channels": [
+            {"name": "bgm.wav", "notes": [{"x": null, "y": 0, "l": 0, "c": false}]},
+            {"name": "piano.wav", "notes": [
+                {"x": 1, "y": 480, "l": 0, "c": false},
+                {"x": 1, "y": 1320, "l": 0, "c": true},
+                {"x": 8, "y": 1680, "l": 720, "c": false}
+            ]}
+        ],
+        "bga": {
+            "bga_header": [{"id": 5, "name": "bg.png"}],
+            "bga_events": [{"y": 0, "id": 5}]
+        }
+    }"#;
+
+    #[test]
+    fn test_parse() {
+        let bms = BmsonParser::new().parse_str(CHART).unwrap();
+        assert!(bms.warnings.is_empty(), "{:?}", bms.warnings);
+        assert_eq!(bms.header.title.as_deref(), Some("Bmson Test"));
+        assert_eq!(bms.header.subartist.as_deref(), Some("obj: a, bga: b"));
+        assert_eq!(bms.header.player, Some(PlayerMode::Single));
+        assert_eq!(bms.header.playlevel, Some(7));
+        assert_eq!(bms.header.total, Some(300.0));
+        assert_eq!(bms.keysounds[&Alphanumeric::from_int(2)], "piano.wav");
+        assert_eq!(bms.bga_layers[&Alphanumeric::from_int(1)], "bg.png");
+        assert_eq!(bms.timing.measure_lengths.get(&1), Some(&0.75));
+
+        let objects: Vec<(f32, u32, bool)> = bms
+            .objects
+            .iter()
+            .map(|o| (o.measure, o.channel, o.continuation))
+            .collect();
+        assert_eq!(
+            objects,
+            vec![
+                (0.0, 1, false),
+                (0.0, 4, false),
+                (0.5, 37, false),
+                (1.5, 37, true),
+                (2.0, 186, false),
+            ]
+        );
+        assert!(matches!(
+            bms.objects[4].objtype,
+            ObjType::LongNote { end_measure, .. } if end_measure == 2.75
+        ));
+    }
+
+    #[test]
+    fn test_same_as_bms() {
+        // The same chart as CHART, written as BMS
+        let bms = BmsParser::new()
+            .parse_str(
+                "#BPM 120\n#BPM01 240\n#STOP01 48\n#WAV01 bgm.wav\n#WAV02 piano.wav\n\
+                 #00001:01\n#00011:0002\n#00102:0.75\n#00108:01\n#00111:0002\n\
+                 #00209:01\n#00256:02000002\n",
+            )
+            .unwrap();
+        let bmson = BmsonParser::new().parse_str(CHART).unwrap();
+        assert_eq!(
+            format!("{:?}", bmson.timeline),
+            format!("{:?}", bms.timeline)
+        );
+        let times = |bms: &BMS| -> Vec<(i64, u32)> {
+            bms.objects
+                .iter()
+                .filter(|o| o.channel != 4)
+                .map(|o| (o.time, o.channel))
+                .collect()
+        };
+        assert_eq!(times(&bmson), times(&bms));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let err = BmsonParser::new().parse_str("{\n\"info\": 5}").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(matches!(err.kind, ParseErrorKind::InvalidBmson(_)));
+
+        let bms = BmsonParser::new()
+            .parse_str(
+                r#"{"info": {"init_bpm": 120},
+                    "sound_channels": [{"name": "a.wav", "notes": [{"x": 40, "y": 0}]}]}"#,
+            )
+            .unwrap();
+        assert_eq!(bms.warnings[0].kind, WarningKind::UnsupportedLane(40));
+        assert!(bms.objects.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_bpm() {
+        let parse = |init_bpm: f64, bpm: f64| {
+            let mut bmson: Bmson = serde_json::from_str(CHART).unwrap();
+            bmson.info.init_bpm = init_bpm;
+            bmson.bpm_events[0].bpm = bpm;
+            BmsonParser::new().parse_bmson(bmson).map(|_| ())
+        };
+        assert!(parse(120.0, 240.0).is_ok());
+        for (init_bpm, bpm) in [
+            (0.0, 240.0),
+            (-120.0, 240.0),
+            (f64::NAN, 240.0),
+            (120.0, 0.0),
+            (120.0, -1.0),
+            (120.0, f64::INFINITY),
+            (120.0, 1e300),
+        ] {
+            let err = parse(init_bpm, bpm).unwrap_err();
+            assert!(
+                matches!(err.kind, ParseErrorKind::InvalidBpm(_)),
+                "{} {}",
+                init_bpm,
+                bpm
+            );
+        }
+        // A missing init_bpm isn't replaced with a default
+        let err = BmsonParser::new().parse_str("{}").unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidBpm(v) if v == "0"));
+    }
+
+    const BMS_CHART: &str = "#TITLE Bmson Test\n#PLAYER 1\n#RANK 3\n#BPM 120\n#BPM01 240\n\
+                             #STOP01 48\n#WAV01 bgm.wav\n#WAV02 piano.wav\n#WAV03 unused.wav\n\
+                             #BMP01 bg.png\n#00001:01\n#00004:01\n#00011:0002\n#00016:0002\n\
+                             #00102:0.75\n#00108:01\n#00111:000002\n#00209:01\n\
+                             #00256:02000002\n";
+
+    #[test]
+    fn test_write() {
+        let bms = BmsParser::new().parse_str(BMS_CHART).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&BmsonWriter::new().write(&bms)).unwrap();
+        assert_eq!(json["version"], "1.0.0");
+        assert_eq!(json["info"]["title"], "Bmson Test");
+        assert_eq!(json["info"]["mode_hint"], "beat-5k");
+        assert_eq!(json["info"]["init_bpm"], 120.0);
+        assert_eq!(json["info"]["judge_rank"], 150.0);
+        assert_eq!(json["info"]["resolution"], 240);
+        assert_eq!(
+            json["lines"],
+            serde_json::json!([{"y": 0}, {"y": 960}, {"y": 1680}, {"y": 2640}])
+        );
+        assert_eq!(
+            json["bpm_events"],
+            serde_json::json!([{"y": 960, "bpm": 240.0}])
+        );
+        assert_eq!(
+            json["stop_events"],
+            serde_json::json!([{"y": 1680, "duration": 240}])
+        );
+        assert_eq!(
+            json["sound_channels"][1],
+            serde_json::json!({"name": "piano.wav", "notes": [
+                {"x": 1, "y": 480, "l": 0, "c": false},
+                {"x": 8, "y": 480, "l": 0, "c": false},
+                {"x": 1, "y": 1440, "l": 0, "c": false},
+                {"x": 8, "y": 1680, "l": 720, "c": false},
+            ]})
+        );
+        assert_eq!(json["sound_channels"][2]["name"], "unused.wav");
+        assert_eq!(
+            json["bga"],
+            serde_json::json!({
+                "bga_header": [{"id": 1, "name": "bg.png"}],
+                "bga_events": [{"y": 0, "id": 1}],
+                "layer_events": [],
+                "poor_events": [],
+            })
+        );
+    }
+
+    #[test]
+    fn test_popn() {
+        let bms = BmsParser::new()
+            .parse_str("#WAV01 a.wav\n#00111:01\n#00125:01\n")
+            .unwrap();
+        let bmson = BmsonWriter::new().to_bmson(&bms);
+        assert_eq!(bmson.info.mode_hint, "popn-9k");
+        let lanes: Vec<u32> = bmson.sound_channels[0].notes.iter().map(|n| n.x).collect();
+        assert_eq!(lanes, vec![1, 9]);
+
+        let bms = BmsonParser::new().parse_bmson(bmson).unwrap();
+        assert_eq!(bms.layout, LaneLayout::PopN);
+        let channels: Vec<u32> = bms.objects.iter().map(|o| o.channel).collect();
+        assert_eq!(channels, vec![37, 77]);
+    }
+
+    #[test]
+    fn test_write_schema() {
+        // bmson 1.0 requires these fields, and none of its fields may be null
+        fn assert_no_nulls(value: &serde_json::Value) {
+            match value {
+                serde_json::Value::Null => panic!("null in bmson output"),
+                serde_json::Value::Array(values) => values.iter().for_each(assert_no_nulls),
+                serde_json::Value::Object(map) => map.values().for_each(assert_no_nulls),
+                _ => {}
+            }
+        }
+        let bms = BmsParser::new().parse_str("#00111:01").unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&BmsonWriter::new().write(&bms)).unwrap();
+        assert_no_nulls(&json);
+        for field in ["version", "info", "sound_channels"] {
+            assert!(json.get(field).is_some(), "missing {}", field);
+        }
+        for field in ["title", "artist", "genre", "init_bpm"] {
+            assert!(json["info"].get(field).is_some(), "missing info.{}", field);
+        }
+        assert!(json["info"].get("back_image").is_none());
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let bms = BmsParser::new().parse_str(BMS_CHART).unwrap();
+        let mut writer = BmsonWriter::new();
+        writer.with_resolution(960);
+        let bmson = BmsonParser::new().parse_str(&writer.write(&bms)).unwrap();
+        assert_eq!(
+            format!("{:?}", bmson.timeline),
+            format!("{:?}", bms.timeline)
+        );
+        let objects = |bms: &BMS| -> Vec<(i64, u32, String)> {
+            bms.objects
+                .iter()
+                .map(|o| {
+                    let key = match o.objtype {
+                        ObjType::BGA { key, .. } => bms.bga_layers[&key].clone(),
+                        ObjType::Auto(key) | ObjType::Note(key) | ObjType::LongNote { key, .. } => {
+                            bms.keysounds[&key].clone()
+                        }
+                        _ => String::new(),
+                    };
+                    (o.time, o.channel, key)
+                })
+                .collect()
+        };
+        // Positions may differ in the last bit of the measure, so times may be 1ms apart
+        let (converted, original) = (objects(&bmson), objects(&bms));
+        assert_eq!(converted.len(), original.len());
+        for ((t1, channel1, name1), (t2, channel2, name2)) in converted.iter().zip(original.iter())
+        {
+            assert!((t1 - t2).abs() <= 1, "{} != {}", t1, t2);
+            assert_eq!((channel1, name1), (channel2, name2));
+        }
+    }
+}
@@ -0,0 +1,65 @@
+/// Detection and decoding of the text encodings BMS files are saved in.
+use encoding::{
+    all::{UTF_8, WINDOWS_31J, WINDOWS_949},
+    DecoderTrap, EncoderTrap, Encoding, EncodingRef,
+};
+use std::borrow::Cow;
+
+const UTF8_BOM: [u8; 3] = [0xEF, 0xBB, 0xBF];
+
+/// The text encoding of a BMS file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BmsEncoding {
+    Utf8,
+    /// Shift_JIS, decoded as its Windows-31J superset. Most Japanese charts use this.
+    ShiftJis,
+    /// EUC-KR, decoded as its Windows-949 superset. Most Korean charts use this.
+    EucKr,
+}
+
+impl BmsEncoding {
+    /// Guesses the encoding of the raw file contents.
+    ///
+    /// A UTF-8 BOM or strictly valid UTF-8 wins, followed by Shift_JIS and then EUC-KR. If the
+    /// contents are invalid in every encoding, Shift_JIS is assumed, as it is by far the most
+    /// common encoding for BMS files.
+    pub fn detect(contents: &[u8]) -> BmsEncoding {
+        if contents.starts_with(&UTF8_BOM) || std::str::from_utf8(contents).is_ok() {
+            BmsEncoding::Utf8
+        } else if WINDOWS_31J.decode(contents, DecoderTrap::Strict).is_ok() {
+            BmsEncoding::ShiftJis
+        } else if WINDOWS_949.decode(contents, DecoderTrap::Strict).is_ok() {
+            BmsEncoding::EucKr
+        } else {
+            BmsEncoding::ShiftJis
+        }
+    }
+
+    /// Decodes the raw file contents, replacing invalid sequences. A leading UTF-8 BOM is
+    /// dropped.
+    pub fn decode(self, contents: &[u8]) -> Result<String, Cow<'static, str>> {
+        let contents = match self {
+            BmsEncoding::Utf8 => contents.strip_prefix(&UTF8_BOM).unwrap_or(contents),
+            _ => contents,
+        };
+        self.codec().decode(contents, DecoderTrap::Replace)
+    }
+
+    /// Encodes text in this encoding, replacing characters that can't be represented.
+    pub fn encode(self, text: &str) -> Result<Vec<u8>, Cow<'static, str>> {
+        self.codec().encode(text, EncoderTrap::Replace)
+    }
+
+    /// Encodes text in this encoding, or returns None if some characters can't be represented.
+    pub fn try_encode(self, text: &str) -> Option<Vec<u8>> {
+        self.codec().encode(text, EncoderTrap::Strict).ok()
+    }
+
+    fn codec(self) -> EncodingRef {
+        match self {
+            BmsEncoding::Utf8 => UTF_8,
+            BmsEncoding::ShiftJis => WINDOWS_31J,
+            BmsEncoding::EucKr => WINDOWS_949,
+        }
+    }
+}
@@ -0,0 +1,364 @@
+/// A lossless, line-by-line model of a BMS file.
+///
+/// A `Document` keeps every source line, including comments, blank lines and commands the
+/// parser doesn't know, along with its original line ending. Writing a document back out
+/// reproduces the source exactly, apart from the lines that were edited. `BmsParser` builds
+/// charts from documents, so a chart can be edited at this level and parsed again.
+use crate::bms::{charset::BmsEncoding, Alphanumeric};
+use std::{fmt, ops::Range};
+
+const UTF8_BOM: &str = "\u{feff}";
+
+/// What a line holds, as far as the syntax is concerned. Ranges are byte offsets into the line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LineKind {
+    /// A line with nothing but whitespace.
+    Empty,
+    /// A line that doesn't start with `#`, after any leading whitespace. BMS treats these as
+    /// comments.
+    Comment,
+    /// A `#mmmcc:data` channel line. The channel is a base-36 number.
+    Channel {
+        measure: u32,
+        channel: u32,
+        data: Range<usize>,
+    },
+    /// Any other `#` command, such as a header, a definition or a control-flow command. The
+    /// name excludes the `#` and ends at the first whitespace; the arguments are the trimmed
+    /// rest of the line.
+    Command {
+        name: Range<usize>,
+        args: Range<usize>,
+    },
+}
+
+/// A single line of a `Document`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Line {
+    text: String,
+    ending: String,
+    span: Option<Range<usize>>,
+    // The undecoded text, written back as-is while the line is unchanged
+    source: Option<Vec<u8>>,
+    kind: LineKind,
+}
+
+impl Line {
+    fn new(text: &str, ending: &str) -> Line {
+        Line {
+            text: text.to_string(),
+            ending: ending.to_string(),
+            span: None,
+            source: None,
+            kind: classify(text),
+        }
+    }
+
+    fn from_source(text: &str, ending: &str, span: Range<usize>, source: &[u8]) -> Line {
+        Line {
+            span: Some(span),
+            source: Some(source.to_vec()),
+            ..Line::new(text, ending)
+        }
+    }
+
+    /// The text of the line, without its line ending.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The line ending: `"\n"`, `"\r\n"`, or empty for a last line without one.
+    pub fn ending(&self) -> &str {
+        &self.ending
+    }
+
+    /// The byte range of the line's text in the source bytes (or source string, for documents
+    /// made with `Document::parse`), or None if the line was added or edited since the document
+    /// was read.
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.span.clone()
+    }
+
+    pub fn kind(&self) -> &LineKind {
+        &self.kind
+    }
+
+    /// Returns the name of a command line, without the `#`.
+    pub fn command_name(&self) -> Option<&str> {
+        match &self.kind {
+            LineKind::Command { name, .. } => Some(&self.text[name.clone()]),
+            _ => None,
+        }
+    }
+
+    /// Returns the arguments of a command line.
+    pub fn command_args(&self) -> Option<&str> {
+        match &self.kind {
+            LineKind::Command { args, .. } => Some(&self.text[args.clone()]),
+            _ => None,
+        }
+    }
+
+    /// Returns the data of a channel line.
+    pub fn channel_data(&self) -> Option<&str> {
+        match &self.kind {
+            LineKind::Channel { data, .. } => Some(&self.text[data.clone()]),
+            _ => None,
+        }
+    }
+}
+
+/// The lines of a BMS file, along with the encoding it is saved in.
+#[derive(Clone, Debug)]
+pub struct Document {
+    lines: Vec<Line>,
+    /// The encoding the document is read and written with.
+    pub encoding: BmsEncoding,
+    // Whether the file started with a UTF-8 byte order mark
+    bom: bool,
+}
+
+impl Document {
+    /// Splits decoded text into a document. The encoding is recorded as UTF-8.
+    pub fn parse(text: &str) -> Document {
+        Document::split(text.as_bytes(), BmsEncoding::Utf8)
+            .expect("UTF-8 text always decodes as UTF-8")
+    }
+
+    /// Decodes the raw contents of a file into a document. Every line keeps its source bytes,
+    /// so unchanged lines encode back to exactly the same bytes, along with a UTF-8 byte order
+    /// mark.
+    pub(crate) fn decode(
+        bms_contents: &[u8],
+        encoding: BmsEncoding,
+    ) -> Result<Document, std::borrow::Cow<'static, str>> {
+        Document::split(bms_contents, encoding)
+    }
+
+    // Splits the raw contents into lines before decoding each of them. A line feed byte never
+    // appears inside a multi-byte character in any of the supported encodings.
+    fn split(
+        bms_contents: &[u8],
+        encoding: BmsEncoding,
+    ) -> Result<Document, std::borrow::Cow<'static, str>> {
+        let bom = encoding == BmsEncoding::Utf8 && bms_contents.starts_with(UTF8_BOM.as_bytes());
+        let mut offset = if bom { UTF8_BOM.len() } else { 0 };
+        let mut lines = Vec::new();
+        for piece in bms_contents[offset..].split_inclusive(|&b| b == b'\n') {
+            let content = piece
+                .strip_suffix(b"\r\n")
+                .or_else(|| piece.strip_suffix(b"\n"))
+                .unwrap_or(piece);
+            let ending = std::str::from_utf8(&piece[content.len()..]).expect("ASCII line ending");
+            let text = encoding.decode(content)?;
+            let span = offset..offset + content.len();
+            lines.push(Line::from_source(&text, ending, span, content));
+            offset += piece.len();
+        }
+        Ok(Document {
+            lines,
+            encoding,
+            bom,
+        })
+    }
+
+    /// Encodes the document in its encoding. Unchanged lines are written as their source
+    /// bytes; in edited lines, characters that can't be represented are replaced.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.bom {
+            bytes.extend_from_slice(UTF8_BOM.as_bytes());
+        }
+        for line in self.lines.iter() {
+            match &line.source {
+                Some(source) => bytes.extend_from_slice(source),
+                None => bytes.extend(
+                    self.encoding
+                        .encode(&line.text)
+                        .unwrap_or_else(|_| line.text.clone().into_bytes()),
+                ),
+            }
+            bytes.extend_from_slice(line.ending.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    pub fn line(&self, index: usize) -> Option<&Line> {
+        self.lines.get(index)
+    }
+
+    /// Replaces the text of a line, keeping its line ending.
+    pub fn set_line(&mut self, index: usize, text: &str) {
+        let line = &mut self.lines[index];
+        *line = Line::new(text, &line.ending);
+    }
+
+    /// Inserts a line before `index`, using the same line ending as the rest of the document.
+    pub fn insert_line(&mut self, index: usize, text: &str) {
+        let ending = match self.lines.first() {
+            Some(line) if line.ending == "\r\n" => "\r\n",
+            _ => "\n",
+        };
+        // The previous last line needs an ending now that it isn't last
+        if index == self.lines.len() {
+            if let Some(last) = self.lines.last_mut() {
+                if last.ending.is_empty() {
+                    last.ending = ending.to_string();
+                }
+            }
+        }
+        self.lines.insert(index, Line::new(text, ending));
+    }
+
+    pub fn remove_line(&mut self, index: usize) -> Line {
+        self.lines.remove(index)
+    }
+
+    /// Returns the indices of the channel lines for the measure and channel, in file order.
+    pub fn find_channel_lines(&self, measure: u32, channel: &str) -> Vec<usize> {
+        let channel = Alphanumeric::from_str(channel).key as u32;
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| {
+                matches!(line.kind, LineKind::Channel { measure: m, channel: c, .. }
+                    if m == measure && c == channel)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.lines.iter() {
+            write!(f, "{}{}", line.text, line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+/// Classifies a line. Leading whitespace before the `#` is allowed, so ranges are offset by it.
+fn classify(line: &str) -> LineKind {
+    let text = line.trim_start();
+    let indent = line.len() - text.len();
+    if text.is_empty() {
+        return LineKind::Empty;
+    }
+    if let Some((measure, channel, data)) = split_channel_line(text) {
+        return LineKind::Channel {
+            measure,
+            channel,
+            data: indent + data.start..indent + data.end,
+        };
+    }
+    match text.strip_prefix('#') {
+        Some(command) => {
+            let name_end = 1 + command.find(char::is_whitespace).unwrap_or(command.len());
+            let args = text[name_end..].trim();
+            let args_start =
+                name_end + (text[name_end..].len() - text[name_end..].trim_start().len());
+            LineKind::Command {
+                name: indent + 1..indent + name_end,
+                args: indent + args_start..indent + args_start + args.len(),
+            }
+        }
+        None => LineKind::Comment,
+    }
+}
+
+/// Splits a `#mmmcc:data` channel line into its measure, base-36 channel and the byte range of
+/// its trimmed data, without going through a regex.
+pub(crate) fn split_channel_line(line: &str) -> Option<(u32, u32, Range<usize>)> {
+    let bytes = line.as_bytes();
+    if bytes.len() < 7
+        || bytes[0] != b'#'
+        || !bytes[1..4].iter().all(u8::is_ascii_digit)
+        || !bytes[4..6].iter().all(u8::is_ascii_alphanumeric)
+        || bytes[6] != b':'
+    {
+        return None;
+    }
+    let measure = line[1..4].parse().ok()?;
+    let channel = u32::from_str_radix(&line[4..6], 36).ok()?;
+    let data = line[7..].trim();
+    let start = 7 + (line[7..].len() - line[7..].trim_start().len());
+    Some((measure, channel, start..start + data.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\u{feff}*---- HEADER\r\n#TITLE  Song \r\n\r\n#FOO bar\r\n#00111:0101\r\n\
+                          ; a comment\r\n#00111:0002";
+
+    #[test]
+    fn test_lossless() {
+        let document = Document::parse(SOURCE);
+        assert_eq!(document.to_string(), &SOURCE[3..]);
+        let bytes = Document::decode(SOURCE.as_bytes(), BmsEncoding::Utf8)
+            .unwrap()
+            .encode();
+        assert_eq!(bytes, SOURCE.as_bytes());
+    }
+
+    #[test]
+    fn test_line_kinds() {
+        let document = Document::parse(SOURCE);
+        let lines = document.lines();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0].kind(), &LineKind::Comment);
+        assert_eq!(lines[1].command_name(), Some("TITLE"));
+        assert_eq!(lines[1].command_args(), Some("Song"));
+        assert_eq!(lines[1].span(), Some(17..30));
+        assert_eq!(lines[2].kind(), &LineKind::Empty);
+        assert_eq!(lines[3].command_name(), Some("FOO"));
+        assert_eq!(lines[4].channel_data(), Some("0101"));
+        assert_eq!(lines[6].ending(), "");
+        assert_eq!(document.find_channel_lines(1, "11"), vec![4, 6]);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut document = Document::parse(&SOURCE[3..]);
+        document.set_line(4, "#00111:01010101");
+        document.insert_line(7, "#00112:01");
+        document.remove_line(2);
+        assert_eq!(
+            document.to_string(),
+            "*---- HEADER\r\n#TITLE  Song \r\n#FOO bar\r\n#00111:01010101\r\n; a comment\r\n\
+             #00111:0002\r\n#00112:01\r\n"
+        );
+        assert_eq!(document.line(3).unwrap().span(), None);
+        assert_eq!(document.line(4).unwrap().span(), Some(54..65));
+    }
+
+    #[test]
+    fn test_lossless_shift_jis() {
+        // NEC and IBM extensions decode to the same characters, so only the source bytes can
+        // tell them apart
+        let source = b"#TITLE \xED\x40\x87\x90\r\n#ARTIST \x83\x65\r\n#00111:01\r\n";
+        let mut document = Document::decode(source, BmsEncoding::ShiftJis).unwrap();
+        assert_eq!(document.encode(), source);
+        assert_eq!(document.line(1).unwrap().span(), Some(13..23));
+        assert_eq!(&source[13..23], b"#ARTIST \x83\x65");
+
+        document.set_line(2, "#00111:02");
+        let mut expected = source[..25].to_vec();
+        expected.extend_from_slice(b"#00111:02\r\n");
+        assert_eq!(document.encode(), expected);
+    }
+
+    #[test]
+    fn test_indented() {
+        let document = Document::parse(" #WAV01 a.wav\n\t#00111:01\n");
+        assert_eq!(document.lines()[0].command_name(), Some("WAV01"));
+        assert_eq!(document.lines()[0].command_args(), Some("a.wav"));
+        assert_eq!(document.lines()[1].channel_data(), Some("01"));
+    }
+}
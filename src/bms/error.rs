@@ -0,0 +1,205 @@
+/// Errors and warnings that can be produced while reading a BMS file.
+use crate::bms::Alphanumeric;
+use std::{error::Error, fmt, io};
+
+/// A fatal error encountered while parsing a BMS file, along with the line that caused it.
+#[derive(Debug)]
+pub struct ParseError {
+    /// The 1-based line number of the offending line, or 0 if the error isn't tied to a line.
+    pub line: usize,
+    /// The raw text of the offending line.
+    pub text: String,
+    /// What went wrong.
+    pub kind: ParseErrorKind,
+}
+
+/// The different kinds of fatal parse errors.
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file contents could not be decoded into text.
+    Decode(String),
+    /// A `#BPM`/`#BPMxx` header or a channel 03 value is not a valid BPM.
+    InvalidBpm(String),
+    /// A `#BPMxx` and an `#EXBPMxx` header define the same key with different values.
+    ConflictingBpm(String),
+    /// A `#STOPxx` header is not a valid STOP duration.
+    InvalidStop(String),
+    /// A `#SCROLLxx` header is not a valid scroll factor.
+    InvalidScroll(String),
+    /// A `#SPEEDxx` header is not a valid speed multiplier.
+    InvalidSpeed(String),
+    /// A `#xxx02` channel line does not hold a valid measure length.
+    InvalidMeasureLength(String),
+    /// A channel line holds an odd number of characters, so it can't be split into objects.
+    OddChannelData,
+    /// A channel line holds characters that can't form objects.
+    InvalidChannelData(String),
+    /// A control-flow command such as `#RANDOM` or `#IF` has an invalid argument.
+    InvalidRandom(String),
+    /// A bmson file isn't valid JSON, or doesn't follow the bmson schema.
+    InvalidBmson(String),
+}
+
+impl ParseError {
+    pub fn new(line: usize, text: &str, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            line,
+            text: text.to_string(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::Io(e) => write!(f, "I/O error: {}", e),
+            ParseErrorKind::Decode(e) => write!(f, "could not decode file: {}", e),
+            ParseErrorKind::InvalidBpm(v) => write!(f, "invalid BPM value {:?}", v),
+            ParseErrorKind::ConflictingBpm(k) => {
+                write!(f, "#BPM{} and #EXBPM{} define different BPMs", k, k)
+            }
+            ParseErrorKind::InvalidStop(v) => write!(f, "invalid STOP value {:?}", v),
+            ParseErrorKind::InvalidScroll(v) => write!(f, "invalid SCROLL value {:?}", v),
+            ParseErrorKind::InvalidSpeed(v) => write!(f, "invalid SPEED value {:?}", v),
+            ParseErrorKind::InvalidMeasureLength(v) => {
+                write!(f, "invalid measure length {:?}", v)
+            }
+            ParseErrorKind::OddChannelData => write!(f, "channel data has an odd length"),
+            ParseErrorKind::InvalidChannelData(v) => write!(f, "invalid channel data {:?}", v),
+            ParseErrorKind::InvalidRandom(v) => write!(f, "invalid control-flow argument {:?}", v),
+            ParseErrorKind::InvalidBmson(e) => write!(f, "invalid bmson: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.kind)
+        } else {
+            write!(f, "line {}: {} ({:?})", self.line, self.kind, self.text)
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            ParseErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::new(0, "", ParseErrorKind::Io(e))
+    }
+}
+
+/// A recoverable problem found while parsing a BMS file. The parser ignores or guesses around
+/// the problem and keeps going.
+#[derive(Debug)]
+pub struct ParseWarning {
+    /// The 1-based line number of the offending line, or 0 if the warning isn't tied to a line.
+    pub line: usize,
+    /// The raw text of the offending line.
+    pub text: String,
+    /// What was ignored or guessed.
+    pub kind: WarningKind,
+}
+
+/// The different kinds of parse warnings.
+#[derive(Debug, PartialEq)]
+pub enum WarningKind {
+    /// A `#WAVxx` key was defined more than once; the last definition is used.
+    DuplicateKeysound(String),
+    /// A channel 08 object references a `#BPMxx` key that was never defined; it is ignored.
+    UndefinedBpm(String),
+    /// A channel 09 object references a `#STOPxx` key that was never defined; it is ignored.
+    UndefinedStop(String),
+    /// A channel SC object references a `#SCROLLxx` key that was never defined; it is ignored.
+    UndefinedScroll(String),
+    /// A channel SP object references a `#SPEEDxx` key that was never defined; it is ignored.
+    UndefinedSpeed(String),
+    /// A header holds a value that can't be parsed into its type; only the raw value is kept.
+    InvalidHeaderValue { header: String, value: String },
+    /// A definition or `#LNOBJ` uses a key that isn't exactly two digits; it is ignored.
+    InvalidKey(String),
+    /// A `#BASE` header sets a base other than 36 or 62; it is ignored.
+    UnsupportedBase(String),
+    /// A bmson note is on a lane that the chart's mode doesn't have; it is ignored.
+    UnsupportedLane(u32),
+    /// A command that isn't recognised by the parser; it is ignored.
+    UnknownCommand(String),
+    /// A control-flow command such as `#ENDIF` or `#CASE` appears outside of a matching block;
+    /// it is ignored.
+    UnmatchedControlFlow(String),
+    /// A long note on the given channel has a start but no end; it is ignored.
+    UnterminatedLongNote(u32),
+    /// An `#LNOBJ` end marker has no preceding note in its lane to end; it is ignored.
+    OrphanLnObj { channel: u32, measure: f32 },
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WarningKind::DuplicateKeysound(k) => write!(f, "#WAV{} is defined more than once", k),
+            WarningKind::UndefinedBpm(k) => write!(f, "#BPM{} is not defined", k),
+            WarningKind::UndefinedStop(k) => write!(f, "#STOP{} is not defined", k),
+            WarningKind::UndefinedScroll(k) => write!(f, "#SCROLL{} is not defined", k),
+            WarningKind::UndefinedSpeed(k) => write!(f, "#SPEED{} is not defined", k),
+            WarningKind::InvalidHeaderValue { header, value } => {
+                write!(f, "invalid #{} value {:?}", header, value)
+            }
+            WarningKind::InvalidKey(k) => write!(f, "invalid key {:?}", k),
+            WarningKind::UnsupportedBase(b) => write!(f, "#BASE {} is not supported", b),
+            WarningKind::UnsupportedLane(x) => write!(f, "bmson lane {} is not supported", x),
+            WarningKind::UnknownCommand(c) => write!(f, "unknown command {:?}", c),
+            WarningKind::UnmatchedControlFlow(c) => write!(f, "#{} has no matching block", c),
+            WarningKind::UnterminatedLongNote(c) => write!(
+                f,
+                "long note on channel {} is never ended",
+                Alphanumeric::from_int(*c as usize).as_base36()
+            ),
+            WarningKind::OrphanLnObj { channel, measure } => write!(
+                f,
+                "#LNOBJ marker on channel {} at measure {} has no note to end",
+                Alphanumeric::from_int(*channel as usize).as_base36(),
+                measure
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.kind)
+        } else {
+            write!(f, "line {}: {} ({:?})", self.line, self.kind, self.text)
+        }
+    }
+}
+
+/// An error that stops a chart from being written as BMS text.
+#[derive(Debug, PartialEq)]
+pub enum WriteError {
+    /// A key is too large to be written with two digits in the chart's base.
+    KeyOutOfRange(Alphanumeric),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::KeyOutOfRange(key) => {
+                write!(f, "key {} doesn't fit in two digits", key.key)
+            }
+        }
+    }
+}
+
+impl Error for WriteError {}
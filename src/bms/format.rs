@@ -1,55 +1,219 @@
 use crate::bms::{
-    timeline::{Timeline, TimelineBuilder},
-    Alphanumeric, Object,
+    charset::BmsEncoding,
+    error::{ParseWarning, WarningKind},
+    header::{Header, PlayerMode},
+    timeline::{Timeline, TimelineBuilder, TimingDefinitions},
+    Alphanumeric, Argb, Base, LaneLayout, ObjType, Object, PlayerSide,
 };
 /// A module for a data structure corresponding to the BMS format, as well as the parser.
 ///
-use std::{collections::HashMap, path::PathBuf, vec::Vec};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    vec::Vec,
+};
 
 #[derive(Debug)]
 pub struct BMS {
     /// BMS file metadata.
-    pub title: String,
-    pub artist: String,
-    pub metadata: HashMap<String, String>,
+    pub header: Header,
     pub objects: Vec<Object>,
+    /// The encoding the file was decoded with.
+    pub encoding: BmsEncoding,
+    /// The path the chart was loaded from, if it was given to the parser.
+    pub path: Option<PathBuf>,
+    /// The base object keys are written in.
+    pub base: Base,
+    /// The button layout the chart is played on, detected from the file extension, `#PLAYER`
+    /// and the channels in use.
+    pub layout: LaneLayout,
 
     // Sound/timeline related fields.
     pub timeline: Timeline,
+    /// The definitions and events the timeline was built from.
+    pub timing: TimingDefinitions,
+    /// The `#LNOBJ` keys, which mark the end of a long note.
+    pub lnobjs: Vec<Alphanumeric>,
     pub keysounds: HashMap<Alphanumeric, String>,
 
     // BGA
     pub bga_layers: HashMap<Alphanumeric, String>,
+    /// The `#ARGBxx` colors that BGA layers are tinted with.
+    pub argbs: HashMap<Alphanumeric, Argb>,
+
+    /// The `#EXRANKxx` judge windows, as percentages of the normal window.
+    pub exranks: HashMap<Alphanumeric, f32>,
+
+    /// Recoverable problems found while parsing, in file order.
+    pub warnings: Vec<ParseWarning>,
+    /// The numbers drawn for each `#RANDOM`/`#SWITCH` block, in order. Parsing again with
+    /// these as `FixedChoices` reproduces the same chart.
+    pub random_choices: Vec<u32>,
+}
+
+impl BMS {
+    /// Resolves an asset path from the chart, such as a `#WAVxx` or `#STAGEFILE` file, against
+    /// the directory of the chart. Returns the path unchanged if the chart has no path.
+    pub fn resolve_asset(&self, asset: &str) -> PathBuf {
+        match self.path.as_ref().and_then(|path| path.parent()) {
+            Some(dir) => dir.join(asset),
+            None => PathBuf::from(asset),
+        }
+    }
+}
+
+/// A single slot of a long-note channel (5x/6x), before it's paired into a long note.
+struct LongNoteMarker {
+    measure: f32,
+    channel: u32,
+    key: Alphanumeric,
+    line: usize,
+    line_text: String,
 }
 
 pub struct BmsBuilder {
-    pub metadata: HashMap<String, String>,
+    pub header: Header,
     pub objects: Vec<Object>,
     pub keysounds: HashMap<Alphanumeric, String>,
     pub bga_layers: HashMap<Alphanumeric, String>,
+    pub argbs: HashMap<Alphanumeric, Argb>,
+    pub exranks: HashMap<Alphanumeric, f32>,
     pub timeline_builder: TimelineBuilder,
+    pub warnings: Vec<ParseWarning>,
+    pub encoding: BmsEncoding,
+    pub path: Option<PathBuf>,
+    pub base: Base,
+    pub random_choices: Vec<u32>,
+    // The layout forced by the caller; detected when the chart is built if not set.
+    layout: Option<LaneLayout>,
+    long_note_markers: Vec<LongNoteMarker>,
+    lnobjs: Vec<Alphanumeric>,
+    // The line of every note by channel, measure bits and key, so an orphan #LNOBJ end marker
+    // can be traced back to its line. The texts are kept once per line.
+    note_lines: HashMap<(u32, u32, Alphanumeric), usize>,
+    note_line_texts: HashMap<usize, String>,
+    // BPM definitions by spelling, to catch #BPMxx and #EXBPMxx disagreeing
+    plain_bpms: HashMap<Alphanumeric, f32>,
+    exbpms: HashMap<Alphanumeric, f32>,
+
+    // The line currently being parsed, so warnings can point back to it.
+    line: usize,
+    line_text: String,
+}
+
+impl Default for BmsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BmsBuilder {
     pub fn new() -> BmsBuilder {
         BmsBuilder {
-            metadata: HashMap::new(),
+            header: Header::default(),
             objects: Vec::new(),
             keysounds: HashMap::new(),
             bga_layers: HashMap::new(),
+            argbs: HashMap::new(),
+            exranks: HashMap::new(),
             timeline_builder: TimelineBuilder::new(),
+            warnings: Vec::new(),
+            encoding: BmsEncoding::Utf8,
+            path: None,
+            base: Base::Base36,
+            random_choices: Vec::new(),
+            layout: None,
+            long_note_markers: Vec::new(),
+            lnobjs: Vec::new(),
+            note_lines: HashMap::new(),
+            note_line_texts: HashMap::new(),
+            plain_bpms: HashMap::new(),
+            exbpms: HashMap::new(),
+            line: 0,
+            line_text: String::new(),
         }
     }
 
-    // Maybe it's better to consume the header and value
+    /// Sets the line currently being parsed. Warnings added afterwards will refer to this line.
+    pub fn at_line(&mut self, line: usize, text: &str) -> &Self {
+        self.line = line;
+        self.line_text = text.to_string();
+        self
+    }
+
+    /// Records a warning for the line currently being parsed.
+    pub fn warn(&mut self, kind: WarningKind) -> &Self {
+        self.warnings.push(ParseWarning {
+            line: self.line,
+            text: self.line_text.clone(),
+            kind,
+        });
+        self
+    }
+
+    pub fn with_encoding(&mut self, encoding: BmsEncoding) -> &Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_path(&mut self, path: &Path) -> &Self {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+
+    /// Records a number drawn for a `#RANDOM`/`#SWITCH` block.
+    pub fn with_random_choice(&mut self, choice: u32) -> &Self {
+        self.random_choices.push(choice);
+        self
+    }
+
+    /// Sets the button layout instead of detecting it.
+    pub fn with_layout(&mut self, layout: LaneLayout) -> &Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    /// Sets the base that object keys are written in from here on.
+    pub fn with_base(&mut self, base: Base) -> &Self {
+        self.base = base;
+        self
+    }
+
+    /// Parses an object key in the chart's base.
+    pub fn parse_key(&self, key: &str) -> Alphanumeric {
+        Alphanumeric::from_str_base(key, self.base)
+    }
+
+    /// Parses the key of a definition such as `#WAVxx`, or of `#LNOBJ`. Keys must be exactly two
+    /// digits; anything else is warned about and None is returned.
+    pub fn parse_definition_key(&mut self, key: &str) -> Option<Alphanumeric> {
+        if key.len() == 2 && key.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            Some(self.parse_key(key))
+        } else {
+            self.warn(WarningKind::InvalidKey(key.to_string()));
+            None
+        }
+    }
+
+    /// Formats an object key in the chart's base.
+    pub fn format_key(&self, key: &Alphanumeric) -> String {
+        key.as_base(self.base)
+    }
+
     pub fn with_metadata(&mut self, header: String, value: String) -> &Self {
-        // self.metadata.insert(header.to_string(), value.to_string());
-        self.metadata.insert(header, value);
+        if !self.header.set(&header, &value) {
+            self.warn(WarningKind::InvalidHeaderValue { header, value });
+        }
         self
     }
 
     // Maybe it's better to consume the path
     pub fn with_keysound(&mut self, keysound_key: Alphanumeric, path: String) -> &Self {
+        if self.keysounds.contains_key(&keysound_key) {
+            self.warn(WarningKind::DuplicateKeysound(
+                self.format_key(&keysound_key),
+            ));
+        }
         self.keysounds.insert(keysound_key, path);
         self
     }
@@ -60,51 +224,329 @@ impl BmsBuilder {
         self
     }
 
+    pub fn with_argb(&mut self, argb_key: Alphanumeric, argb: Argb) -> &Self {
+        self.argbs.insert(argb_key, argb);
+        self
+    }
+
+    pub fn with_exrank(&mut self, exrank_key: Alphanumeric, exrank: f32) -> &Self {
+        self.exranks.insert(exrank_key, exrank);
+        self
+    }
+
     pub fn with_bpm(&mut self, bpm_key: Alphanumeric, bpm: f32) -> &Self {
+        self.plain_bpms.insert(bpm_key, bpm);
         self.timeline_builder.with_bpm(bpm_key, bpm);
         self
     }
 
+    /// Adds an `#EXBPMxx` definition. It shares its keys with `#BPMxx`, so channel 08 can
+    /// reference either spelling.
+    pub fn with_exbpm(&mut self, bpm_key: Alphanumeric, bpm: f32) -> &Self {
+        self.exbpms.insert(bpm_key, bpm);
+        self.timeline_builder.with_bpm(bpm_key, bpm);
+        self
+    }
+
+    /// Returns whether the other spelling (`#BPMxx` if `extended`, `#EXBPMxx` otherwise)
+    /// already defines the key with a different BPM.
+    pub fn conflicts_with_bpm(&self, bpm_key: &Alphanumeric, bpm: f32, extended: bool) -> bool {
+        let other = if extended {
+            &self.plain_bpms
+        } else {
+            &self.exbpms
+        };
+        other
+            .get(bpm_key)
+            .is_some_and(|&other_bpm| other_bpm != bpm)
+    }
+
     pub fn with_stop(&mut self, stop_key: Alphanumeric, stop: f32) -> &Self {
         self.timeline_builder.with_stop(stop_key, stop);
         self
     }
 
+    pub fn with_scroll(&mut self, scroll_key: Alphanumeric, scroll: f32) -> &Self {
+        self.timeline_builder.with_scroll(scroll_key, scroll);
+        self
+    }
+
+    pub fn with_speed(&mut self, speed_key: Alphanumeric, speed: f32) -> &Self {
+        self.timeline_builder.with_speed(speed_key, speed);
+        self
+    }
+
     pub fn add_object(&mut self, object: Object) -> &Self {
+        if let ObjType::Note(key) = object.objtype {
+            self.note_lines
+                .entry((object.channel, object.measure.to_bits(), key))
+                .or_insert(self.line);
+            if !self.note_line_texts.contains_key(&self.line) {
+                self.note_line_texts
+                    .insert(self.line, self.line_text.clone());
+            }
+        }
         self.objects.push(object);
         self
     }
 
+    /// Adds a slot of a long-note channel, including empty "00" slots. Markers are paired into
+    /// long notes according to `#LNTYPE` when the chart is built.
+    pub fn add_long_note_marker(&mut self, measure: f32, channel: u32, key: Alphanumeric) -> &Self {
+        self.long_note_markers.push(LongNoteMarker {
+            measure,
+            channel,
+            key,
+            line: self.line,
+            line_text: self.line_text.clone(),
+        });
+        self
+    }
+
+    /// Pairs the long-note channel markers into long-note objects.
+    ///
+    /// With `#LNTYPE 1` (the default), every two non-empty slots of a channel form the start and
+    /// end of a long note. With `#LNTYPE 2`, a long note lasts for as long as the same key
+    /// repeats in consecutive slots, and ends at the first empty slot, different key, or
+    /// measure without a line for that channel.
+    fn add_long_notes(&mut self) {
+        let lntype = self.header.long_note_type();
+        let mut markers = std::mem::take(&mut self.long_note_markers);
+        // The sort is stable, so markers at the same position stay in file order
+        markers.sort_by(|m1, m2| {
+            m1.channel
+                .cmp(&m2.channel)
+                .then(m1.measure.partial_cmp(&m2.measure).unwrap())
+        });
+
+        for channel_markers in markers.chunk_by(|m1, m2| m1.channel == m2.channel) {
+            let mut head: Option<&LongNoteMarker> = None;
+            if lntype == 2 {
+                let mut last_measure = 0_f32;
+                for marker in channel_markers {
+                    if let Some(h) = head {
+                        let next_measure = last_measure.floor() + 1_f32;
+                        if marker.measure.floor() > next_measure {
+                            self.objects.push(long_note(h, next_measure));
+                            head = None;
+                        } else if marker.key != h.key {
+                            self.objects.push(long_note(h, marker.measure));
+                            head = None;
+                        }
+                    }
+                    if head.is_none() && marker.key.key != 0 {
+                        head = Some(marker);
+                    }
+                    last_measure = marker.measure;
+                }
+                if let Some(h) = head {
+                    self.objects
+                        .push(long_note(h, last_measure.floor() + 1_f32));
+                }
+            } else {
+                for marker in channel_markers.iter().filter(|m| m.key.key != 0) {
+                    match head.take() {
+                        Some(h) => self.objects.push(long_note(h, marker.measure)),
+                        None => head = Some(marker),
+                    }
+                }
+                if let Some(h) = head {
+                    self.warnings.push(ParseWarning {
+                        line: h.line,
+                        text: h.line_text.clone(),
+                        kind: WarningKind::UnterminatedLongNote(h.channel),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Adds an `#LNOBJ` key. Notes with this key end the previous note in their lane as a long
+    /// note.
+    pub fn with_lnobj(&mut self, key: Alphanumeric) -> &Self {
+        self.lnobjs.push(key);
+        self
+    }
+
+    /// Returns whether the key was declared with `#LNOBJ`.
+    pub fn is_lnobj(&self, key: &Alphanumeric) -> bool {
+        self.lnobjs.contains(key)
+    }
+
+    /// Turns each note preceding an `#LNOBJ` end marker into a long note, and removes the end
+    /// markers. Objects must already be sorted by measure.
+    fn add_lnobj_long_notes(&mut self) {
+        if self.lnobjs.is_empty() {
+            return;
+        }
+
+        // The last note in each lane that can still be turned into a long note
+        let mut heads: HashMap<u32, usize> = HashMap::new();
+        let mut end_markers: Vec<usize> = Vec::new();
+        for i in 0..self.objects.len() {
+            let Object {
+                channel, measure, ..
+            } = self.objects[i];
+            let key = match self.objects[i].objtype {
+                ObjType::Note(key) => key,
+                _ => continue,
+            };
+            if !self.lnobjs.contains(&key) {
+                heads.insert(channel, i);
+                continue;
+            }
+
+            end_markers.push(i);
+            match heads.remove(&channel) {
+                Some(head) => {
+                    if let ObjType::Note(head_key) = self.objects[head].objtype {
+                        self.objects[head].objtype = ObjType::LongNote {
+                            key: head_key,
+                            end_measure: measure,
+                            end_time: 0,
+                        };
+                    }
+                }
+                None => {
+                    let line = self
+                        .note_lines
+                        .get(&(channel, measure.to_bits(), key))
+                        .copied()
+                        .unwrap_or(0);
+                    self.warnings.push(ParseWarning {
+                        line,
+                        text: self.note_line_texts.get(&line).cloned().unwrap_or_default(),
+                        kind: WarningKind::OrphanLnObj { channel, measure },
+                    });
+                }
+            }
+        }
+
+        let mut index = 0;
+        self.objects.retain(|_| {
+            index += 1;
+            end_markers.binary_search(&(index - 1)).is_err()
+        });
+    }
+
+    /// Detects the button layout from the path, `#PLAYER` and the lanes in use.
+    fn detect_layout(&self) -> LaneLayout {
+        let mut lanes = LaneUsage::default();
+        for object in self.objects.iter() {
+            lanes.add(object.channel);
+        }
+        for marker in self.long_note_markers.iter().filter(|m| m.key.key != 0) {
+            lanes.add(marker.channel);
+        }
+        lanes.layout(self.path.as_deref(), self.header.player)
+    }
+
     pub fn build(mut self) -> BMS {
-        let title = dbg!(self
-            .metadata
-            .get("TITLE")
-            .unwrap_or(&"MISSING TITLE".to_string())
-            .to_string());
-        let artist = dbg!(self
-            .metadata
-            .get("ARTIST")
-            .unwrap_or(&"MISSING ARTIST".to_string())
-            .to_string());
-
-        // Sort objects by measure
-        self.objects
-            .sort_by(|o1, o2| o1.measure.partial_cmp(&o2.measure).unwrap());
+        let layout = self.layout.unwrap_or_else(|| self.detect_layout());
+        self.add_long_notes();
+
+        // Every pop'n button belongs to the single player
+        if layout == LaneLayout::PopN {
+            for object in self.objects.iter_mut() {
+                if object.side.is_some() {
+                    object.side = Some(PlayerSide::Player1);
+                }
+            }
+        }
+
+        // Sort objects by measure, then by channel so the order doesn't depend on the line order
+        self.objects.sort_by(|o1, o2| {
+            o1.measure
+                .partial_cmp(&o2.measure)
+                .unwrap()
+                .then(o1.channel.cmp(&o2.channel))
+        });
+        self.add_lnobj_long_notes();
 
         // Pre-build the timeline, so the object positions can be cached
         let timeline = self.timeline_builder.build();
-        for mut object in self.objects.iter_mut() {
+        for object in self.objects.iter_mut() {
             object.time = timeline.time_from_measure(object.measure);
+            if let ObjType::LongNote {
+                end_measure,
+                ref mut end_time,
+                ..
+            } = object.objtype
+            {
+                *end_time = timeline.time_from_measure(end_measure);
+            }
         }
 
         BMS {
-            title,
-            artist,
-            metadata: self.metadata,
+            header: self.header,
             objects: self.objects,
+            encoding: self.encoding,
+            path: self.path,
+            base: self.base,
+            layout,
             timeline,
+            timing: self.timeline_builder.into_definitions(),
+            lnobjs: self.lnobjs,
             keysounds: self.keysounds,
             bga_layers: self.bga_layers,
+            argbs: self.argbs,
+            exranks: self.exranks,
+            warnings: self.warnings,
+            random_choices: self.random_choices,
         }
     }
 }
+
+/// The lanes a chart puts objects on, used to tell pop'n charts apart from beatmania ones.
+/// `BmsBuilder` and the header scan both detect the layout through this.
+#[derive(Default)]
+pub(crate) struct LaneUsage {
+    // Whether any object is on pop'n buttons 6-9, the 2P channels 22-25
+    popn_buttons: bool,
+    // Whether any object is on a lane that pop'n charts don't have
+    beat_lanes: bool,
+}
+
+impl LaneUsage {
+    /// Records an object on a channel. Channels without a lane, such as BGM and BGA channels,
+    /// are ignored.
+    pub(crate) fn add(&mut self, channel: u32) {
+        if PlayerSide::from_channel(channel).is_none() {
+            return;
+        }
+        match LaneLayout::PopN.lane(channel) {
+            Some(lane) => self.popn_buttons |= lane > 5,
+            None => self.beat_lanes = true,
+        }
+    }
+
+    /// Returns the layout of the chart. `.pms` files are always pop'n charts; other charts are
+    /// if they're for a single player and only use the pop'n lanes, including at least one of
+    /// the 2P channels 22-25 that a single beatmania player wouldn't use.
+    pub(crate) fn layout(&self, path: Option<&Path>, player: Option<PlayerMode>) -> LaneLayout {
+        let pms_path = path
+            .and_then(|path| path.extension())
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("pms"));
+        let single = matches!(player, None | Some(PlayerMode::Single));
+        if pms_path || (single && self.popn_buttons && !self.beat_lanes) {
+            LaneLayout::PopN
+        } else {
+            LaneLayout::Beat
+        }
+    }
+}
+
+/// Creates the head object of a long note that starts at `head` and ends at `end_measure`.
+fn long_note(head: &LongNoteMarker, end_measure: f32) -> Object {
+    Object {
+        measure: head.measure,
+        channel: head.channel,
+        side: PlayerSide::from_channel(head.channel),
+        objtype: ObjType::LongNote {
+            key: head.key,
+            end_measure,
+            end_time: 0,
+        },
+        ..Default::default()
+    }
+}
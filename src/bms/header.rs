@@ -0,0 +1,213 @@
+/// The typed header fields of a chart, such as `#TITLE` and `#PLAYLEVEL`.
+use std::collections::HashMap;
+
+/// The play style set by `#PLAYER`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PlayerMode {
+    /// `#PLAYER 1`: a single 5/7-key side.
+    Single,
+    /// `#PLAYER 2`: two players, one on each side.
+    Couple,
+    /// `#PLAYER 3`: one player on both sides (10/14 keys).
+    Double,
+    /// `#PLAYER 4`: two players sharing the same chart.
+    Battle,
+}
+
+/// The difficulty label set by `#DIFFICULTY`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Difficulty {
+    Beginner,
+    Normal,
+    Hyper,
+    Another,
+    Insane,
+}
+
+/// The judge window set by `#RANK`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JudgeRank {
+    VeryHard,
+    Hard,
+    Normal,
+    Easy,
+    VeryEasy,
+}
+
+/// How long notes on the long-note channels are judged, as set by beatoraja's `#LNMODE`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LongNoteMode {
+    /// `#LNMODE 1`: only the start of the note is judged.
+    LongNote,
+    /// `#LNMODE 2`: both the start and the release are judged.
+    ChargeNote,
+    /// `#LNMODE 3`: like a charge note, but the gauge keeps changing while the note is held.
+    HellChargeNote,
+}
+
+/// The header of a chart. Every field is `None` if its header is missing or holds a value that
+/// couldn't be parsed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Header {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub artist: Option<String>,
+    pub subartist: Option<String>,
+    pub genre: Option<String>,
+    pub player: Option<PlayerMode>,
+    pub playlevel: Option<u32>,
+    pub difficulty: Option<Difficulty>,
+    pub rank: Option<JudgeRank>,
+    /// The judge window as a percentage of the `#RANK 2` (normal) window.
+    pub exrank: Option<f32>,
+    /// The total gauge increase over the whole chart.
+    pub total: Option<f32>,
+    /// The volume of every keysound, as a percentage.
+    pub volwav: Option<u32>,
+    pub stagefile: Option<String>,
+    pub banner: Option<String>,
+    pub backbmp: Option<String>,
+    pub preview: Option<String>,
+    pub comment: Option<String>,
+    pub lnmode: Option<LongNoteMode>,
+    /// The unparsed value of every header, by header name. These are kept even when a value
+    /// couldn't be parsed, so the chart can be written back out unchanged.
+    pub raw: HashMap<String, String>,
+}
+
+impl Header {
+    /// Sets a header from its raw value. Returns false if the value couldn't be parsed into the
+    /// field's type, in which case only the raw value is kept.
+    pub fn set(&mut self, header: &str, value: &str) -> bool {
+        self.raw.insert(header.to_string(), value.to_string());
+        let text = || Some(value.to_string());
+        match header {
+            "TITLE" => self.title = text(),
+            "SUBTITLE" => self.subtitle = text(),
+            "ARTIST" => self.artist = text(),
+            "SUBARTIST" => self.subartist = text(),
+            "GENRE" => self.genre = text(),
+            "PLAYER" => {
+                self.player = match value {
+                    "1" => Some(PlayerMode::Single),
+                    "2" => Some(PlayerMode::Couple),
+                    "3" => Some(PlayerMode::Double),
+                    "4" => Some(PlayerMode::Battle),
+                    _ => None,
+                };
+                return self.player.is_some();
+            }
+            "PLAYLEVEL" => {
+                self.playlevel = value.parse().ok();
+                return self.playlevel.is_some();
+            }
+            "DIFFICULTY" => {
+                self.difficulty = match value {
+                    "1" => Some(Difficulty::Beginner),
+                    "2" => Some(Difficulty::Normal),
+                    "3" => Some(Difficulty::Hyper),
+                    "4" => Some(Difficulty::Another),
+                    "5" => Some(Difficulty::Insane),
+                    _ => None,
+                };
+                return self.difficulty.is_some();
+            }
+            "RANK" => {
+                self.rank = match value {
+                    "0" => Some(JudgeRank::VeryHard),
+                    "1" => Some(JudgeRank::Hard),
+                    "2" => Some(JudgeRank::Normal),
+                    "3" => Some(JudgeRank::Easy),
+                    "4" => Some(JudgeRank::VeryEasy),
+                    _ => None,
+                };
+                return self.rank.is_some();
+            }
+            "DEFEXRANK" => {
+                self.exrank = value.parse().ok();
+                return self.exrank.is_some();
+            }
+            "TOTAL" => {
+                self.total = value.parse().ok();
+                return self.total.is_some();
+            }
+            "VOLWAV" => {
+                self.volwav = value.parse().ok();
+                return self.volwav.is_some();
+            }
+            "STAGEFILE" => self.stagefile = text(),
+            "BANNER" => self.banner = text(),
+            "BACKBMP" => self.backbmp = text(),
+            "PREVIEW" => self.preview = text(),
+            "LNMODE" => {
+                self.lnmode = match value {
+                    "1" => Some(LongNoteMode::LongNote),
+                    "2" => Some(LongNoteMode::ChargeNote),
+                    "3" => Some(LongNoteMode::HellChargeNote),
+                    _ => None,
+                };
+                return self.lnmode.is_some();
+            }
+            // Comments are usually quoted
+            "COMMENT" => {
+                let unquoted = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                self.comment = Some(unquoted.to_string());
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Returns the `#LNTYPE` long notes are paired with, read as a number so `#LNTYPE 02` is
+    /// type 2. Defaults to 1 if the header is missing or isn't a number.
+    pub fn long_note_type(&self) -> u32 {
+        self.raw
+            .get("LNTYPE")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut header = Header::default();
+        assert!(header.set("SUBTITLE", "[ANOTHER]"));
+        assert!(header.set("PLAYER", "3"));
+        assert!(header.set("DIFFICULTY", "4"));
+        assert!(header.set("DEFEXRANK", "120"));
+        assert!(header.set("COMMENT", "\"hello\""));
+        assert_eq!(header.subtitle.as_deref(), Some("[ANOTHER]"));
+        assert_eq!(header.player, Some(PlayerMode::Double));
+        assert_eq!(header.difficulty, Some(Difficulty::Another));
+        assert_eq!(header.exrank, Some(120.0));
+        assert_eq!(header.comment.as_deref(), Some("hello"));
+        assert_eq!(header.raw["COMMENT"], "\"hello\"");
+    }
+
+    #[test]
+    fn test_set_invalid() {
+        let mut header = Header::default();
+        assert!(!header.set("PLAYLEVEL", "12+"));
+        assert!(!header.set("RANK", "9"));
+        assert_eq!(header.playlevel, None);
+        assert_eq!(header.rank, None);
+        assert_eq!(header.raw["PLAYLEVEL"], "12+");
+    }
+
+    #[test]
+    fn test_long_note_type() {
+        let mut header = Header::default();
+        assert_eq!(header.long_note_type(), 1);
+        header.set("LNTYPE", "02");
+        assert_eq!(header.long_note_type(), 2);
+        header.set("LNTYPE", "x");
+        assert_eq!(header.long_note_type(), 1);
+    }
+}
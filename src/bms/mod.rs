@@ -1,22 +1,58 @@
 /// Collections of structs, functions, and consts common to everything in the BMS module.
+pub mod bmson;
+pub mod charset;
+pub mod document;
+pub mod error;
 pub mod format;
+pub mod header;
 pub mod parser;
+pub mod random;
 pub mod timeline;
+pub mod writer;
 
-const BASE36: &'static str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const BASE36: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const BASE62: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
 
-/// A hexadecimal representation of an "object". Takes the range 00-ZZ.
-#[derive(Debug, Eq, Hash, PartialEq)]
+/// The base object keys are written in, as set by the `#BASE` header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Base {
+    /// Keys 00-ZZ, where lowercase letters are the same as uppercase ones. The default.
+    Base36,
+    /// Case-sensitive keys 00-zz.
+    Base62,
+}
+
+/// A hexadecimal representation of an "object". Takes the range 00-ZZ, or 00-zz in base 62.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Alphanumeric {
     pub key: usize,
 }
 
 impl Alphanumeric {
-    /// Create an Alphanumeric from a str. Defaults to an Alphanumeric of key 0 if the str is invalid.
+    /// Create an Alphanumeric from a two-digit base-36 str. Defaults to an Alphanumeric of key 0
+    /// if the str is invalid.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(key: &str) -> Alphanumeric {
-        Alphanumeric {
-            key: usize::from_str_radix(key, 36).unwrap_or(0),
-        }
+        Alphanumeric::from_str_base(key, Base::Base36)
+    }
+
+    /// Create an Alphanumeric from a str of exactly two digits in the given base, so keys stay
+    /// within 00-ZZ (0-1295) or 00-zz (0-3843). Defaults to an Alphanumeric of key 0 if the str
+    /// is invalid.
+    pub fn from_str_base(key: &str, base: Base) -> Alphanumeric {
+        let digit = |c: char| match base {
+            Base::Base36 => c.to_digit(36).map(|d| d as usize),
+            Base::Base62 => BASE62.find(c),
+        };
+        let mut chars = key.chars();
+        let key = match (chars.next(), chars.next(), chars.next()) {
+            (Some(high), Some(low), None) => match (digit(high), digit(low)) {
+                (Some(high), Some(low)) => high * base.radix() + low,
+                _ => 0,
+            },
+            _ => 0,
+        };
+        Alphanumeric { key }
     }
 
     /// Create an Alphanumeric from an int.
@@ -24,23 +60,104 @@ impl Alphanumeric {
         Alphanumeric { key }
     }
 
-    /// Returns a base-36 string representation of the Alphanumeric key.
+    /// Returns whether the key can be written with two digits in the given base.
+    pub fn fits(&self, base: Base) -> bool {
+        self.key < base.radix() * base.radix()
+    }
+
+    /// Returns a base-36 string representation of the Alphanumeric key. Keys past ZZ, which
+    /// can't be written in a chart, get more digits.
     pub fn as_base36(&self) -> String {
-        // There is definitely a more elegant way to do this :)
-        let mut s = String::new();
-        s.push(BASE36.chars().nth(self.key / 36).expect("Bad alphanumeric"));
-        s.push(BASE36.chars().nth(self.key % 36).expect("Bad alphanumeric"));
-        s
+        format_digits(self.key, BASE36)
+    }
+
+    /// Returns a base-62 string representation of the Alphanumeric key. Keys past zz get more
+    /// digits.
+    pub fn as_base62(&self) -> String {
+        format_digits(self.key, BASE62)
+    }
+
+    /// Returns a string representation of the Alphanumeric key in the given base.
+    pub fn as_base(&self, base: Base) -> String {
+        match base {
+            Base::Base36 => self.as_base36(),
+            Base::Base62 => self.as_base62(),
+        }
+    }
+}
+
+impl Base {
+    fn radix(self) -> usize {
+        match self {
+            Base::Base36 => 36,
+            Base::Base62 => 62,
+        }
     }
 }
 
+/// Writes a number with the given digits, using at least two of them.
+fn format_digits(mut value: usize, digits: &str) -> String {
+    let digits = digits.as_bytes();
+    let mut s = Vec::new();
+    while s.len() < 2 || value > 0 {
+        s.push(digits[value % digits.len()]);
+        value /= digits.len();
+    }
+    s.reverse();
+    String::from_utf8(s).expect("digits are ASCII")
+}
+
 impl std::fmt::Display for Alphanumeric {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Keys past ZZ only fit in base 62
+        if self.fits(Base::Base36) {
+            write!(
+                f,
+                "Alphanumeric: {} (Base 36: {})",
+                self.key,
+                self.as_base36()
+            )
+        } else {
+            write!(
+                f,
+                "Alphanumeric: {} (Base 62: {})",
+                self.key,
+                self.as_base62()
+            )
+        }
+    }
+}
+
+/// The layers a BGA is composited from, from bottom to top. The poor layer replaces the
+/// others for a moment when the player misses a note.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BgaLayer {
+    /// Channel 04
+    Base,
+    /// Channel 07
+    Layer,
+    /// Channel 0A
+    Layer2,
+    /// Channel 06
+    Poor,
+}
+
+/// A color defined by beatoraja's `#ARGBxx a,r,g,b` header, used to tint a BGA layer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Argb {
+    pub alpha: u8,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl std::fmt::Display for Argb {
+    /// Formats the color the way `#ARGBxx` writes it.
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Alphanumeric: {} (Base 36: {})",
-            self.key,
-            self.as_base36()
+            "{},{},{},{}",
+            self.alpha, self.red, self.green, self.blue
         )
     }
 }
@@ -49,9 +166,37 @@ impl std::fmt::Display for Alphanumeric {
 #[derive(Debug)]
 pub enum ObjType {
     Auto(Alphanumeric), // Keysound
-    BGA(Alphanumeric),
+    /// Shows the `#BMPxx` image with the key `key` on a BGA layer.
+    BGA {
+        layer: BgaLayer,
+        key: Alphanumeric,
+    },
+    /// Sets the opacity of a BGA layer, from 0 (transparent) to 255 (opaque).
+    BGAOpacity {
+        layer: BgaLayer,
+        opacity: u8,
+    },
+    /// Tints a BGA layer with the `#ARGBxx` color with the key `key` (beatoraja's channels
+    /// 0G-0J).
+    BGAArgb {
+        layer: BgaLayer,
+        key: Alphanumeric,
+    },
     Note(Alphanumeric),
-    LongNote(f32), // Denotes ending measure of a long note object
+    /// A note that is never drawn or judged, but plays its keysound when its lane is pressed.
+    Invisible(Alphanumeric),
+    /// A landmine, which damages the gauge by `damage` when its lane is pressed. The damage is
+    /// the base-36 value written in the chart; ZZ conventionally means instant failure.
+    Mine {
+        damage: u32,
+    },
+    /// The head of a long note, which is held until `end_measure`.
+    LongNote {
+        key: Alphanumeric,
+        end_measure: f32,
+        /// The time in milliseconds that the long note ends, resolved through the timeline.
+        end_time: i64,
+    },
 }
 
 impl Default for ObjType {
@@ -60,13 +205,119 @@ impl Default for ObjType {
     }
 }
 
+/// The side of the play field an object belongs to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PlayerSide {
+    Player1,
+    Player2,
+}
+
+impl PlayerSide {
+    /// Returns the side that a lane channel (1x/2x, 3x/4x, 5x/6x, Dx/Ex) belongs to, or None
+    /// for channels that aren't tied to a side.
+    pub fn from_channel(channel: u32) -> Option<PlayerSide> {
+        // Channels are base-36, so this is the first digit of the channel
+        match channel / 36 {
+            1 | 3 | 5 | 13 => Some(PlayerSide::Player1),
+            2 | 4 | 6 | 14 => Some(PlayerSide::Player2),
+            _ => None,
+        }
+    }
+}
+
+/// The button layout a chart is played on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LaneLayout {
+    /// beatmania: up to 7 keys and a scratch on each side, on channels 11-19 and 21-29.
+    Beat,
+    /// pop'n music: 9 buttons on channels 11-15 and 22-25, played by a single player. `.pms`
+    /// charts use this layout.
+    PopN,
+}
+
+/// The second channel digit of lanes 1-8 of a beatmania side: keys 1-7, then the scratch.
+const BEAT_LANES: [u32; 8] = [1, 2, 3, 4, 5, 8, 9, 6];
+/// The channels of pop'n buttons 1-9, from left to right.
+const POPN_LANES: [u32; 9] = [37, 38, 39, 40, 41, 74, 75, 76, 77];
+
+impl LaneLayout {
+    /// Returns the lane of a note channel, counting from 1 at the left, or None if the channel
+    /// isn't part of the layout. Invisible, long-note and landmine channels share the lanes of
+    /// the note channels. beatmania lanes 1-8 are the 1P keys and scratch, and 9-16 the 2P ones.
+    pub fn lane(self, channel: u32) -> Option<u32> {
+        let side = match channel / 36 {
+            1 | 3 | 5 | 13 => 1,
+            2 | 4 | 6 | 14 => 2,
+            _ => return None,
+        };
+        let index = match self {
+            LaneLayout::Beat => {
+                (side - 1) * 8 + BEAT_LANES.iter().position(|&d| d == channel % 36)? as u32
+            }
+            LaneLayout::PopN => POPN_LANES
+                .iter()
+                .position(|&c| c == side * 36 + channel % 36)?
+                as u32,
+        };
+        Some(index + 1)
+    }
+
+    /// Returns the note channel (1x/2x) of a lane.
+    pub fn channel(self, lane: u32) -> Option<u32> {
+        match self {
+            LaneLayout::Beat => match lane {
+                1..=8 => Some(36 + BEAT_LANES[lane as usize - 1]),
+                9..=16 => Some(2 * 36 + BEAT_LANES[lane as usize - 9]),
+                _ => None,
+            },
+            LaneLayout::PopN => match lane {
+                1..=9 => Some(POPN_LANES[lane as usize - 1]),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns the judge windows of the layout at `#RANK 2`, as beatoraja plays it.
+    pub fn judge_windows(self) -> JudgeWindows {
+        match self {
+            LaneLayout::Beat => JudgeWindows {
+                perfect: 20,
+                great: 60,
+                good: 150,
+                bad: 220,
+            },
+            LaneLayout::PopN => JudgeWindows {
+                perfect: 20,
+                great: 50,
+                good: 117,
+                bad: 183,
+            },
+        }
+    }
+}
+
+/// How far from a note a hit can be to get each judgement, in milliseconds either way.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct JudgeWindows {
+    pub perfect: u32,
+    pub great: u32,
+    pub good: u32,
+    pub bad: u32,
+}
+
 /// An "object" in a BMS file, represented as a
 #[derive(Debug, Default)]
 pub struct Object {
     pub time: i64,
     pub measure: f32,
+    /// The channel the object was read from, as a base-36 number (e.g. channel "D1" is 469).
     pub channel: u32,
     pub objtype: ObjType,
+    /// The side the object's lane belongs to, or None for background objects.
+    pub side: Option<PlayerSide>,
+    /// Whether the keysound carries on from where the previous object with the same keysound
+    /// cut it off, instead of restarting. Only bmson charts (the `c` flag) slice sounds this way.
+    pub continuation: bool,
 
     // Timing offset from when the note was hit, in measures
     // Initializes to None, which can be used to determine whether this note has already been hit.
@@ -101,4 +352,74 @@ mod tests {
         let a = Alphanumeric::from_int(1193);
         assert_eq!(a.as_base36(), "X5");
     }
+
+    #[test]
+    fn test_from_str_base() {
+        assert_eq!(Alphanumeric::from_str_base("x5", Base::Base36).key, 1193);
+        assert_eq!(Alphanumeric::from_str_base("X5", Base::Base62).key, 2051);
+        assert_eq!(Alphanumeric::from_str_base("x5", Base::Base62).key, 3663);
+        assert_eq!(Alphanumeric::from_str_base("zz", Base::Base62).key, 3843);
+        assert_eq!(Alphanumeric::from_str_base("!@", Base::Base62).key, 0);
+    }
+
+    #[test]
+    fn test_from_str_length() {
+        assert_eq!(Alphanumeric::from_str("1A2").key, 0);
+        assert_eq!(Alphanumeric::from_str("Z").key, 0);
+        assert_eq!(Alphanumeric::from_str_base("zzz", Base::Base62).key, 0);
+        assert_eq!(Alphanumeric::from_str_base("", Base::Base62).key, 0);
+    }
+
+    #[test]
+    fn test_format_out_of_range() {
+        let a = Alphanumeric::from_int(46655);
+        assert!(!a.fits(Base::Base36));
+        assert_eq!(a.as_base36(), "ZZZ");
+        assert_eq!(Alphanumeric::from_int(3844).as_base62(), "100");
+        assert!(Alphanumeric::from_int(3843).fits(Base::Base62));
+        assert_eq!(
+            Alphanumeric::from_int(5000).to_string(),
+            "Alphanumeric: 5000 (Base 62: 1Ie)"
+        );
+    }
+
+    #[test]
+    fn test_as_base62() {
+        let a = Alphanumeric::from_int(3663);
+        assert_eq!(a.as_base62(), "x5");
+        assert_eq!(a.as_base(Base::Base62), "x5");
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            Alphanumeric::from_int(1193).to_string(),
+            "Alphanumeric: 1193 (Base 36: X5)"
+        );
+        assert_eq!(
+            Alphanumeric::from_int(3000).to_string(),
+            "Alphanumeric: 3000 (Base 62: mO)"
+        );
+        assert_eq!(
+            Alphanumeric::from_int(3843).to_string(),
+            "Alphanumeric: 3843 (Base 62: zz)"
+        );
+    }
+
+    #[test]
+    fn test_lanes() {
+        let ch = |channel| u32::from_str_radix(channel, 36).unwrap();
+        assert_eq!(LaneLayout::Beat.lane(ch("16")), Some(8));
+        assert_eq!(LaneLayout::Beat.lane(ch("29")), Some(15));
+        assert_eq!(LaneLayout::Beat.lane(ch("17")), None);
+        assert_eq!(LaneLayout::PopN.lane(ch("15")), Some(5));
+        assert_eq!(LaneLayout::PopN.lane(ch("62")), Some(6));
+        assert_eq!(LaneLayout::PopN.lane(ch("D5")), Some(5));
+        assert_eq!(LaneLayout::PopN.lane(ch("21")), None);
+        for layout in [LaneLayout::Beat, LaneLayout::PopN] {
+            for lane in 1..=9 {
+                assert_eq!(layout.lane(layout.channel(lane).unwrap()), Some(lane));
+            }
+        }
+    }
 }
@@ -1,131 +1,533 @@
 use crate::bms::{
-    format::{BmsBuilder, BMS},
+    charset::BmsEncoding,
+    document::{split_channel_line, Document, LineKind},
+    error::{ParseError, ParseErrorKind, WarningKind},
+    format::{BmsBuilder, LaneUsage, BMS},
+    header::{Header, PlayerMode},
+    random::{ChoiceEnumerator, ControlFlow, FixedChoices, RandomSource, XorShiftRng},
     timeline::TimelineEvent,
-    Alphanumeric, ObjType, Object,
+    Alphanumeric, Argb, Base, BgaLayer, LaneLayout, ObjType, Object, PlayerSide,
 };
-use encoding::{all::UTF_8, DecoderTrap, Encoding};
 use regex::Regex;
-use std::{fs::File, io::Read, str::FromStr};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-const METADATA_HEADERS: [&'static str; 8] = [
+/// Every header that is a plain value, rather than a keyed definition like `#WAVxx`. This
+/// includes the LR2 and beatoraja extensions.
+pub(crate) const METADATA_HEADERS: [&str; 27] = [
     "PLAYER",
     "GENRE",
     "TITLE",
+    "SUBTITLE",
     "ARTIST",
-    // "BPM",
+    "SUBARTIST",
+    "MAKER",
     "PLAYLEVEL",
+    "DIFFICULTY",
     "RANK",
+    "DEFEXRANK",
     "TOTAL",
+    "VOLWAV",
     "STAGEFILE",
+    "BANNER",
+    "BACKBMP",
+    "PREVIEW",
+    "COMMENT",
+    "LNTYPE",
+    "LNMODE",
+    "MIDIFILE",
+    "VIDEOFILE",
+    "PATH_WAV",
+    "CHARFILE",
+    "EMAIL",
+    "URL",
+    "CHARSET",
 ];
 
-pub struct BmsParser;
-
-/// Temporary BPM struct
+/// Every outcome of a chart's `#RANDOM`/`#SWITCH` blocks, as returned by
+/// `BmsParser::parse_all_random`.
 #[derive(Debug)]
-pub struct BPM {
-    measure: f32,
-    bpm: f32,
+pub struct RandomVariants {
+    /// One chart per combination of branch choices. The choices for each chart are in its
+    /// `random_choices` field.
+    pub charts: Vec<BMS>,
+    /// Whether there were more combinations than the requested maximum.
+    pub truncated: bool,
 }
 
+impl RandomVariants {
+    /// Returns the branch choices of every chart, in the same order as `charts`.
+    pub fn choices(&self) -> Vec<&[u32]> {
+        self.charts
+            .iter()
+            .map(|bms| bms.random_choices.as_slice())
+            .collect()
+    }
+}
+
+/// The header of a chart along with a few statistics, as returned by
+/// `BmsParser::scan_header`.
 #[derive(Debug)]
-pub struct STOP {
-    measure: f32,
-    stop_val: f32, // Most #STOP values are integers
+pub struct HeaderScan {
+    pub header: Header,
+    /// The lowest BPM the chart reaches.
+    pub min_bpm: f32,
+    /// The highest BPM the chart reaches.
+    pub max_bpm: f32,
+    /// The `#PLAYER` mode, or a guess from the lanes in use if the header is missing or invalid.
+    pub player: PlayerMode,
+    /// The button layout, detected the same way as `BMS::layout`.
+    pub layout: LaneLayout,
+    /// The number of notes to be hit, where a long note counts as one note.
+    pub note_count: usize,
+}
+
+/// Gathers the statistics of a `HeaderScan` from the channel lines.
+#[derive(Default)]
+struct HeaderScanner {
+    bpms: Vec<f32>,
+    bpm_keys: Vec<Alphanumeric>,
+    // Whether a channel 03 BPM change, or which channel 08 key, is at the very start of the
+    // chart, where it replaces the base BPM
+    start_bpm: bool,
+    start_bpm_key: Option<Alphanumeric>,
+    // Played notes by key, as #LNOBJ end markers can only be told apart once the file is read
+    notes: HashMap<Alphanumeric, usize>,
+    long_note_markers: Vec<(u32, f32, Alphanumeric)>,
+    uses_player2: bool,
+    lanes: LaneUsage,
+}
+
+impl HeaderScanner {
+    fn scan_channel(&mut self, measure: u32, channel: u32, data: &str, bms_builder: &BmsBuilder) {
+        if !data.is_ascii() || !data.len().is_multiple_of(2) {
+            return;
+        }
+        let slots = (0..data.len()).step_by(2).map(|i| &data[i..i + 2]);
+        if slots.clone().any(|slot| slot != "00") {
+            self.lanes.add(channel);
+        }
+        match (channel / 36, channel % 36) {
+            (0, 3) => {
+                for (i, hex) in slots.enumerate() {
+                    match u16::from_str_radix(hex, 16) {
+                        Ok(bpm) if bpm != 0 => {
+                            self.start_bpm |= measure == 0 && i == 0;
+                            self.bpms.push(f32::from(bpm));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            (0, 8) => {
+                for (i, key) in slots.enumerate() {
+                    let key = bms_builder.parse_key(key);
+                    if key.key != 0 {
+                        if measure == 0 && i == 0 {
+                            self.start_bpm_key = Some(key);
+                        }
+                        self.bpm_keys.push(key);
+                    }
+                }
+            }
+            (1..=2, 1..=9) => {
+                for key in slots.map(|key| bms_builder.parse_key(key)) {
+                    if key.key != 0 {
+                        *self.notes.entry(key).or_insert(0) += 1;
+                        self.uses_player2 |= channel / 36 == 2;
+                    }
+                }
+            }
+            (5..=6, 1..=9) => {
+                let len = data.len() as f32;
+                for (i, key) in slots.enumerate() {
+                    let key = bms_builder.parse_key(key);
+                    let position = measure as f32 + (i * 2) as f32 / len;
+                    self.long_note_markers.push((channel, position, key));
+                    self.uses_player2 |= key.key != 0 && channel / 36 == 6;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(mut self, bms_builder: BmsBuilder) -> HeaderScan {
+        let timeline_builder = &bms_builder.timeline_builder;
+        // Like TimelineBuilder::build, the base BPM is never played if a change replaces it at
+        // the start
+        let start_bpm = self.start_bpm
+            || self
+                .start_bpm_key
+                .is_some_and(|key| timeline_builder.find_bpm(&key).is_some());
+        let base_bpm = if start_bpm {
+            None
+        } else {
+            Some(timeline_builder.base_bpm())
+        };
+        let bpms = base_bpm.into_iter().chain(self.bpms).chain(
+            self.bpm_keys
+                .iter()
+                .filter_map(|key| timeline_builder.find_bpm(key)),
+        );
+        let (min_bpm, max_bpm) = bpms.fold((f32::MAX, f32::MIN), |(min, max), bpm| {
+            (min.min(bpm), max.max(bpm))
+        });
+
+        // #LNOBJ end markers turn the note before them into a long note
+        let mut note_count: usize = self
+            .notes
+            .iter()
+            .filter(|(key, _)| !bms_builder.is_lnobj(key))
+            .map(|(_, count)| count)
+            .sum();
+        // Long notes are paired the same way as BmsBuilder::add_long_notes does it
+        let lntype = bms_builder.header.long_note_type();
+        self.long_note_markers
+            .sort_by(|m1, m2| m1.0.cmp(&m2.0).then(m1.1.partial_cmp(&m2.1).unwrap()));
+        for markers in self.long_note_markers.chunk_by(|m1, m2| m1.0 == m2.0) {
+            if lntype == 2 {
+                let mut head: Option<Alphanumeric> = None;
+                let mut last_measure = 0_f32;
+                for &(_, measure, key) in markers {
+                    if let Some(h) = head {
+                        if measure.floor() > last_measure.floor() + 1_f32 || key != h {
+                            note_count += 1;
+                            head = None;
+                        }
+                    }
+                    if head.is_none() && key.key != 0 {
+                        head = Some(key);
+                    }
+                    last_measure = measure;
+                }
+                note_count += usize::from(head.is_some());
+            } else {
+                note_count += markers.iter().filter(|m| m.2.key != 0).count() / 2;
+            }
+        }
+
+        let layout = self
+            .lanes
+            .layout(bms_builder.path.as_deref(), bms_builder.header.player);
+        // pop'n charts use 2P channels for a single player
+        let player = bms_builder.header.player.unwrap_or(
+            if self.uses_player2 && layout == LaneLayout::Beat {
+                PlayerMode::Double
+            } else {
+                PlayerMode::Single
+            },
+        );
+        HeaderScan {
+            header: bms_builder.header,
+            min_bpm,
+            max_bpm,
+            player,
+            layout,
+            note_count,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BmsParser {
+    // Encoding forced by the caller; detected from the file contents if not set.
+    encoding: Option<BmsEncoding>,
+    // Where the chart was loaded from, used to resolve its assets.
+    path: Option<PathBuf>,
 }
 
 impl BmsParser {
-    /// Parses the file
-    /// TODO: Make a better documentation
-    pub fn parse(&self, file: &mut File) -> BMS {
+    pub fn new() -> BmsParser {
+        BmsParser {
+            encoding: None,
+            path: None,
+        }
+    }
+
+    /// Forces files to be decoded with the given encoding instead of detecting it.
+    pub fn with_encoding(&mut self, encoding: BmsEncoding) -> &Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Sets the path the chart was loaded from. It is only used to resolve the chart's assets
+    /// with `BMS::resolve_asset`; the chart itself is always read from the given input.
+    pub fn with_path<P: AsRef<Path>>(&mut self, path: P) -> &Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Parses the file into a BMS chart.
+    /// Returns a `ParseError` holding the offending line if the file can't be read or contains
+    /// malformed data. `#RANDOM` branches are chosen with a time-seeded random number generator.
+    pub fn parse(&self, file: &mut File) -> Result<BMS, ParseError> {
+        self.parse_reader(file)
+    }
+
+    /// Parses a chart from any reader, such as a file or an archive entry.
+    pub fn parse_reader<R: Read>(&self, reader: R) -> Result<BMS, ParseError> {
+        self.parse_with_rng(reader, &mut XorShiftRng::from_time())
+    }
+
+    /// Parses a chart from its raw bytes, detecting the encoding unless one is forced.
+    pub fn parse_bytes(&self, bms_contents: &[u8]) -> Result<BMS, ParseError> {
+        self.parse_contents(bms_contents, &mut XorShiftRng::from_time())
+    }
+
+    /// Parses a chart that has already been decoded. Its encoding is recorded as UTF-8, unless
+    /// one is forced.
+    pub fn parse_str(&self, text: &str) -> Result<BMS, ParseError> {
+        let mut document = Document::parse(text);
+        document.encoding = self.encoding.unwrap_or(BmsEncoding::Utf8);
+        self.parse_document(&document)
+    }
+
+    /// Builds a chart from a document, such as one that was edited after `read_document`.
+    pub fn parse_document(&self, document: &Document) -> Result<BMS, ParseError> {
+        self.build_document(document, &mut XorShiftRng::from_time())
+    }
+
+    /// Decodes the raw contents of a BMS file into a lossless `Document`, detecting the
+    /// encoding unless one is forced.
+    pub fn read_document(&self, bms_contents: &[u8]) -> Result<Document, ParseError> {
+        let encoding = self.encoding(bms_contents);
+        Document::decode(bms_contents, encoding)
+            .map_err(|e| ParseError::new(0, "", ParseErrorKind::Decode(e.into_owned())))
+    }
+
+    /// Parses a chart from any reader, choosing `#RANDOM` and `#SWITCH` branches with `rng`.
+    pub fn parse_with_rng<R: Read>(
+        &self,
+        mut reader: R,
+        rng: &mut dyn RandomSource,
+    ) -> Result<BMS, ParseError> {
         let mut bms_contents = Vec::new();
-        file.read_to_end(&mut bms_contents)
-            .expect("File reading error");
+        reader.read_to_end(&mut bms_contents)?;
+        self.parse_contents(&bms_contents, rng)
+    }
 
-        let mut parsers: Vec<Box<BmsLineParser>> = Vec::new();
-        parsers.push(Box::new(MetadataParser {}));
-        parsers.push(Box::new(WavParser::new()));
-        parsers.push(Box::new(BgaParser::new()));
-        parsers.push(Box::new(BpmParser::new()));
-        parsers.push(Box::new(ObjParser::new()));
-        parsers.push(Box::new(StopParser::new()));
+    /// Parses the chart once for every combination of `#RANDOM`/`#SWITCH` branch choices.
+    /// At most `max_variants` charts are built; `RandomVariants::truncated` tells whether any
+    /// combinations were left out.
+    pub fn parse_all_random<R: Read>(
+        &self,
+        mut reader: R,
+        max_variants: usize,
+    ) -> Result<RandomVariants, ParseError> {
+        let mut bms_contents = Vec::new();
+        reader.read_to_end(&mut bms_contents)?;
+        self.parse_all_random_contents(&bms_contents, max_variants)
+    }
+
+    fn parse_all_random_contents(
+        &self,
+        bms_contents: &[u8],
+        max_variants: usize,
+    ) -> Result<RandomVariants, ParseError> {
+        let mut charts = Vec::new();
+        let mut enumerator = ChoiceEnumerator::new();
+        loop {
+            if charts.len() >= max_variants {
+                return Ok(RandomVariants {
+                    charts,
+                    truncated: true,
+                });
+            }
+            charts.push(self.parse_contents(bms_contents, &mut enumerator)?);
+            if !enumerator.advance() {
+                return Ok(RandomVariants {
+                    charts,
+                    truncated: false,
+                });
+            }
+        }
+    }
+
+    /// Parses the raw contents of a BMS file.
+    fn parse_contents(
+        &self,
+        bms_contents: &[u8],
+        rng: &mut dyn RandomSource,
+    ) -> Result<BMS, ParseError> {
+        self.build_document(&self.read_document(bms_contents)?, rng)
+    }
+
+    /// Builds a chart from the lines of a document.
+    fn build_document(
+        &self,
+        document: &Document,
+        rng: &mut dyn RandomSource,
+    ) -> Result<BMS, ParseError> {
+        let parsers: Vec<Box<dyn BmsLineParser>> = vec![
+            Box::new(MetadataParser {}),
+            Box::new(WavParser::new()),
+            Box::new(BgaParser::new()),
+            Box::new(BpmParser::new()),
+            Box::new(ObjParser::new()),
+            Box::new(StopParser::new()),
+            Box::new(LnObjParser::new()),
+            Box::new(ScrollParser::new()),
+            Box::new(SpeedParser::new()),
+            Box::new(BaseParser::new()),
+            Box::new(ExRankParser::new()),
+            Box::new(ArgbParser::new()),
+        ];
 
         let mut bms_builder = BmsBuilder::new();
-        for line in UTF_8
-            .decode(&bms_contents, DecoderTrap::Replace)
-            .expect("Could not decode line in UTF-8")
-            .lines()
-        {
+        bms_builder.with_encoding(document.encoding);
+        if let Some(path) = &self.path {
+            bms_builder.with_path(path);
+        }
+        let mut control_flow = ControlFlow::new();
+        for (line_index, line) in document.lines().iter().enumerate() {
+            // Blank lines and comments can't hold anything
+            if let LineKind::Empty | LineKind::Comment = line.kind() {
+                continue;
+            }
+            bms_builder.at_line(line_index + 1, line.text());
+            // Commands may be indented
+            let line = line.text().trim_start();
+            match control_flow.process(line, rng, &mut bms_builder) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(kind) => return Err(ParseError::new(line_index + 1, line, kind)),
+            }
+            if !control_flow.is_active() {
+                continue;
+            }
+
+            let mut handled = false;
             for line_parser in parsers.iter() {
-                if line_parser.parse_line_into_bms(&line.into(), &mut bms_builder) {
-                    break;
+                match line_parser.parse_line_into_bms(line, &mut bms_builder) {
+                    Ok(true) => {
+                        handled = true;
+                        break;
+                    }
+                    Ok(false) => continue,
+                    Err(kind) => return Err(ParseError::new(line_index + 1, line, kind)),
                 }
             }
+            if !handled {
+                let command = line.split_whitespace().next().unwrap_or(line);
+                bms_builder.warn(WarningKind::UnknownCommand(command.to_string()));
+            }
         }
-        let bms = bms_builder.build();
 
-        bms
+        Ok(bms_builder.build())
     }
 
-    /// Parses the title of the chart from the given file.
-    /// TODO: Make a better documentation
-    fn parse_title(&self, file: &mut File) -> Option<String> {
+    /// Reads the header of the chart along with its BPM range, player mode and note count,
+    /// without building the timeline or any objects. This is much faster than `parse`, so it's
+    /// suited to indexing large numbers of charts.
+    ///
+    /// The first branch of every `#RANDOM`/`#SWITCH` block is scanned.
+    pub fn scan_header<R: Read>(&self, mut reader: R) -> Result<HeaderScan, ParseError> {
         let mut bms_contents = Vec::new();
-        file.read_to_end(&mut bms_contents)
-            .expect("File reading error");
-
-        let metadata_parser: MetadataParser = MetadataParser {};
-        for line in UTF_8
-            .decode(&bms_contents, DecoderTrap::Replace)
-            .expect("Could not decode line in UTF-8")
-            .lines()
-        {
-            let result = metadata_parser.parse_line(&line.into());
-            if result.is_none() {
+        reader.read_to_end(&mut bms_contents)?;
+        self.scan_header_contents(&bms_contents)
+    }
+
+    fn scan_header_contents(&self, bms_contents: &[u8]) -> Result<HeaderScan, ParseError> {
+        // Only the parsers for headers and definitions; channel lines are scanned by hand
+        let parsers: Vec<Box<dyn BmsLineParser>> = vec![
+            Box::new(MetadataParser {}),
+            Box::new(BpmParser::new()),
+            Box::new(LnObjParser::new()),
+            Box::new(BaseParser::new()),
+        ];
+
+        let encoding = self.encoding(bms_contents);
+        let mut bms_builder = BmsBuilder::new();
+        if let Some(path) = &self.path {
+            bms_builder.with_path(path);
+        }
+        let mut control_flow = ControlFlow::new();
+        let mut rng = FixedChoices::default();
+        let mut scanner = HeaderScanner::default();
+        for (line_index, line) in decode(bms_contents, encoding)?.lines().enumerate() {
+            bms_builder.at_line(line_index + 1, line);
+            let line = line.trim_start();
+            match control_flow.process(line, &mut rng, &mut bms_builder) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(kind) => return Err(ParseError::new(line_index + 1, line, kind)),
+            }
+            if !control_flow.is_active() {
                 continue;
             }
 
-            let value = result.unwrap();
-            if "TITLE".eq(&value.0) {
-                return Option::from(value.1);
+            if let Some((measure, channel, data)) = split_channel_line(line) {
+                scanner.scan_channel(measure, channel, &line[data], &bms_builder);
+                continue;
+            }
+            for line_parser in parsers.iter() {
+                match line_parser.parse_line_into_bms(line, &mut bms_builder) {
+                    Ok(true) => break,
+                    Ok(false) => continue,
+                    Err(kind) => return Err(ParseError::new(line_index + 1, line, kind)),
+                }
             }
         }
-        Option::None
+
+        Ok(scanner.finish(bms_builder))
     }
+
+    /// Returns the forced encoding, or detects it from the file contents.
+    fn encoding(&self, bms_contents: &[u8]) -> BmsEncoding {
+        self.encoding
+            .unwrap_or_else(|| BmsEncoding::detect(bms_contents))
+    }
+}
+
+/// Decodes the raw contents of a BMS file into text.
+fn decode(bms_contents: &[u8], encoding: BmsEncoding) -> Result<String, ParseError> {
+    encoding
+        .decode(bms_contents)
+        .map_err(|e| ParseError::new(0, "", ParseErrorKind::Decode(e.into_owned())))
 }
 
 trait BmsLineParser {
-    fn parse_line_into_bms(&self, line: &String, bms_builder: &mut BmsBuilder) -> bool;
-    fn parse_line(&self, line: &String) -> Option<(String, String)>;
+    /// Parses the line into the builder. Returns `Ok(false)` if the line isn't handled by this
+    /// parser.
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind>;
+    fn parse_line(&self, line: &str) -> Option<(String, String)>;
 }
 
 struct MetadataParser;
 
 impl BmsLineParser for MetadataParser {
-    fn parse_line_into_bms(&self, line: &String, bms_builder: &mut BmsBuilder) -> bool {
-        let result = self.parse_line(line);
-        if result.is_none() {
-            return false;
-        }
-
-        let value = result.unwrap();
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
         bms_builder.with_metadata(value.0, value.1);
-        true
+        Ok(true)
     }
 
-    fn parse_line(&self, line: &String) -> Option<(String, String)> {
-        if !line.starts_with("#") {
-            return Option::None;
-        }
-
-        let entry = &line[1..];
-        for header in METADATA_HEADERS.iter() {
-            if entry.starts_with(header) {
-                let keydata = (header.to_string(), entry[header.len()..].trim().to_string());
-                return Option::from(keydata);
-            }
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let entry = line.strip_prefix('#')?;
+        // The header must match exactly, so #PLAYER doesn't also match #PLAYLEVEL
+        let (header, value) = entry.split_once(char::is_whitespace).unwrap_or((entry, ""));
+        let header = header.to_ascii_uppercase();
+        if METADATA_HEADERS.contains(&header.as_str()) {
+            Option::from((header, value.trim().to_string()))
+        } else {
+            Option::None
         }
-        Option::None
     }
 }
 
@@ -136,32 +538,30 @@ struct WavParser {
 impl WavParser {
     pub fn new() -> WavParser {
         WavParser {
-            regex_parser: Regex::new(r"#WAV(?P<key>.+?) (?P<data>.*)")
+            regex_parser: Regex::new(r"(?i)#WAV(?P<key>[0-9A-Za-z]+) (?P<data>.*)")
                 .expect("Could not initialize regex"),
         }
     }
 }
 
 impl BmsLineParser for WavParser {
-    fn parse_line_into_bms(&self, line: &String, bms_builder: &mut BmsBuilder) -> bool {
-        let result = self.parse_line(line);
-        if result.is_none() {
-            return false;
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if let Some(key) = bms_builder.parse_definition_key(&value.0) {
+            bms_builder.with_keysound(key, value.1);
         }
-
-        let value = result.unwrap();
-        let key = Alphanumeric::from_str(&value.0);
-        bms_builder.with_keysound(key, value.1);
-        true
+        Ok(true)
     }
 
-    fn parse_line(&self, line: &String) -> Option<(String, String)> {
-        let capture_res = self.regex_parser.captures(line);
-        if capture_res.is_none() {
-            return Option::None;
-        }
-
-        let res = capture_res.unwrap();
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
         let (key, data): (&str, &str) = (&res["key"], &res["data"]);
         let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
         Option::from(keydata)
@@ -175,32 +575,30 @@ struct BgaParser {
 impl BgaParser {
     pub fn new() -> BgaParser {
         BgaParser {
-            regex_parser: Regex::new(r"#BMP(?P<key>.+?) (?P<data>.*)")
+            regex_parser: Regex::new(r"(?i)#BMP(?P<key>[0-9A-Za-z]+) (?P<data>.*)")
                 .expect("Could not initialize regex"),
         }
     }
 }
 
 impl BmsLineParser for BgaParser {
-    fn parse_line_into_bms(&self, line: &String, bms_builder: &mut BmsBuilder) -> bool {
-        let result = self.parse_line(line);
-        if result.is_none() {
-            return false;
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if let Some(key) = bms_builder.parse_definition_key(&value.0) {
+            bms_builder.with_bga_layer(key, value.1);
         }
-
-        let value = result.unwrap();
-        let key = Alphanumeric::from_str(&value.0);
-        bms_builder.with_bga_layer(key, value.1);
-        true
+        Ok(true)
     }
 
-    fn parse_line(&self, line: &String) -> Option<(String, String)> {
-        let capture_res = self.regex_parser.captures(line);
-        if capture_res.is_none() {
-            return Option::None;
-        }
-
-        let res = capture_res.unwrap();
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
         let (key, data): (&str, &str) = (&res["key"], &res["data"]);
         let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
         Option::from(keydata)
@@ -214,38 +612,46 @@ struct BpmParser {
 impl BpmParser {
     pub fn new() -> BpmParser {
         BpmParser {
-            // Should've been  "(?P<key>.{0}|.{2})", but it doesn't seem to work. Let's make do with 0,2 length.
-            regex_parser: Regex::new(r"#BPM(?P<key>.{0,2}) (?P<data>.*)")
+            // Matches both #BPMxx and the older #EXBPMxx spelling. The key is empty for #BPM.
+            regex_parser: Regex::new(r"(?i)#(?P<ex>EX)?BPM(?P<key>.{0,2}) (?P<data>.*)")
                 .expect("Could not initialize regex"),
         }
     }
 }
 
 impl BmsLineParser for BpmParser {
-    fn parse_line_into_bms(&self, line: &String, bms_builder: &mut BmsBuilder) -> bool {
-        let result = self.parse_line(line);
-        if result.is_none() {
-            return false;
-        }
-
-        let value = result.unwrap();
-        let key = Alphanumeric::from_str(&value.0);
-        let bpm = f32::from_str(&value.1).expect("Invalid data found on BPM header.");
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let key = bms_builder.parse_key(&value.0);
+        let bpm = parse_bpm(&value.1)?;
+        let extended = line
+            .get(..6)
+            .is_some_and(|command| command.eq_ignore_ascii_case("#EXBPM"));
         if key.key == 0 {
             bms_builder.timeline_builder.with_base_bpm(bpm);
         } else {
-            bms_builder.with_bpm(key, bpm);
+            // #BPMxx and #EXBPMxx share their keys, so they must agree with each other
+            if bms_builder.conflicts_with_bpm(&key, bpm, extended) {
+                return Err(ParseErrorKind::ConflictingBpm(bms_builder.format_key(&key)));
+            }
+            if extended {
+                bms_builder.with_exbpm(key, bpm);
+            } else {
+                bms_builder.with_bpm(key, bpm);
+            }
         }
-        true
+        Ok(true)
     }
 
-    fn parse_line(&self, line: &String) -> Option<(String, String)> {
-        let capture_res = self.regex_parser.captures(line);
-        if capture_res.is_none() {
-            return Option::None;
-        }
-
-        let res = capture_res.unwrap();
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
         let (key, data): (&str, &str) = (&res["key"], &res["data"]);
 
         let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
@@ -260,33 +666,40 @@ struct ObjParser {
 impl ObjParser {
     pub fn new() -> ObjParser {
         ObjParser {
-            regex_parser: Regex::new(r"#(?P<measure>[0-9]{3})(?P<channel>[0-9]{2}):(?P<data>.*)")
-                .expect("Could not initialize regex"),
+            regex_parser: Regex::new(
+                r"#(?P<measure>[0-9]{3})(?P<channel>[0-9A-Za-z]{2}):(?P<data>.*)",
+            )
+            .expect("Could not initialize regex"),
         }
     }
 }
 
 impl BmsLineParser for ObjParser {
-    fn parse_line_into_bms(&self, line: &String, bms_builder: &mut BmsBuilder) -> bool {
-        let capture_res = self.regex_parser.captures(line);
-        if capture_res.is_none() {
-            return false;
-        }
-
-        let res = capture_res.unwrap();
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let res = match self.regex_parser.captures(line) {
+            Some(res) => res,
+            None => return Ok(false),
+        };
+        // Both numbers are guaranteed to be valid by the regex. Channels are base-36.
         let (measure, channel, data): (u32, u32, &str) = (
             res["measure"].parse::<u32>().unwrap(),
-            res["channel"].parse::<u32>().unwrap(),
-            &res["data"].trim(),
+            u32::from_str_radix(&res["channel"], 36).unwrap(),
+            res["data"].trim(),
         );
 
-        match channel {
-            1 | 11 | 12 | 13 | 14 | 15 | 16 | 18 | 19 | 4 => {
-                // Autoplay + played notes + BGA
+        // Match on the two digits of the channel
+        match (channel / 36, channel % 36) {
+            (0, 1) | (1..=4, 1..=9) | (0, 4) | (0, 6) | (0, 7) | (0, 10) | (0, 16..=19) => {
+                // Autoplay + 1P/2P played and invisible notes + BGA layers and their colors
+                check_channel_data(data)?;
                 let mut iter = 0;
                 while iter < data.len() {
                     let n_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
-                    let n_keysound = Alphanumeric::from_str(&data[iter..iter + 2]);
+                    let n_keysound = bms_builder.parse_key(&data[iter..iter + 2]);
                     // Prepare. Take note iter has been changed here!
                     iter += 2;
 
@@ -295,9 +708,35 @@ impl BmsLineParser for ObjParser {
                         continue;
                     }
 
-                    let objtype = match channel {
-                        4 => ObjType::BGA(n_keysound),
-                        1 => ObjType::Auto(n_keysound),
+                    let objtype = match (channel / 36, channel % 36) {
+                        (0, 4) => ObjType::BGA {
+                            layer: BgaLayer::Base,
+                            key: n_keysound,
+                        },
+                        (0, 6) => ObjType::BGA {
+                            layer: BgaLayer::Poor,
+                            key: n_keysound,
+                        },
+                        (0, 7) => ObjType::BGA {
+                            layer: BgaLayer::Layer,
+                            key: n_keysound,
+                        },
+                        (0, 10) => ObjType::BGA {
+                            layer: BgaLayer::Layer2,
+                            key: n_keysound,
+                        },
+                        // BGA layer colors (0G base, 0H layer, 0I layer 2, 0J poor)
+                        (0, 16..=19) => ObjType::BGAArgb {
+                            layer: match channel {
+                                16 => BgaLayer::Base,
+                                17 => BgaLayer::Layer,
+                                18 => BgaLayer::Layer2,
+                                _ => BgaLayer::Poor,
+                            },
+                            key: n_keysound,
+                        },
+                        (0, 1) => ObjType::Auto(n_keysound),
+                        (3..=4, _) => ObjType::Invisible(n_keysound),
                         _ => ObjType::Note(n_keysound),
                     };
 
@@ -306,27 +745,109 @@ impl BmsLineParser for ObjParser {
                         measure: n_measure,
                         channel,
                         objtype,
+                        side: PlayerSide::from_channel(channel),
+                        continuation: false,
                         hit_offset: None,
                         longnote_hit_offset: None,
                     };
 
                     bms_builder.add_object(obj);
                 }
-                return true;
+                Ok(true)
             }
-            3 | 8 => {
+            (0, 11..=14) => {
+                // BGA layer opacity (0B base, 0C layer, 0D layer 2, 0E poor), in hexadecimal
+                check_channel_data(data)?;
+                let layer = match channel {
+                    11 => BgaLayer::Base,
+                    12 => BgaLayer::Layer,
+                    13 => BgaLayer::Layer2,
+                    _ => BgaLayer::Poor,
+                };
+                let mut iter = 0;
+                while iter < data.len() {
+                    let n_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
+                    let hex = &data[iter..iter + 2];
+                    let opacity = u8::from_str_radix(hex, 16)
+                        .map_err(|_| ParseErrorKind::InvalidChannelData(hex.to_string()))?;
+                    iter += 2;
+
+                    // "00" is a placeholder, like in every other channel
+                    if opacity == 0 {
+                        continue;
+                    }
+
+                    bms_builder.add_object(Object {
+                        measure: n_measure,
+                        channel,
+                        objtype: ObjType::BGAOpacity { layer, opacity },
+                        ..Default::default()
+                    });
+                }
+                Ok(true)
+            }
+            (5..=6, 1..=9) => {
+                // Long notes; paired up once the whole chart is read
+                check_channel_data(data)?;
+                let mut iter = 0;
+                while iter < data.len() {
+                    let n_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
+                    let n_keysound = bms_builder.parse_key(&data[iter..iter + 2]);
+                    bms_builder.add_long_note_marker(n_measure, channel, n_keysound);
+                    iter += 2;
+                }
+                Ok(true)
+            }
+            (13..=14, 1..=9) => {
+                // Landmines on D1-D9/E1-E9; the data is the damage instead of a keysound
+                check_channel_data(data)?;
+                let mut iter = 0;
+                while iter < data.len() {
+                    let n_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
+                    let damage = Alphanumeric::from_str(&data[iter..iter + 2]).key as u32;
+                    iter += 2;
+
+                    if damage == 0 {
+                        continue;
+                    }
+
+                    bms_builder.add_object(Object {
+                        measure: n_measure,
+                        channel,
+                        objtype: ObjType::Mine { damage },
+                        side: PlayerSide::from_channel(channel),
+                        ..Default::default()
+                    });
+                }
+                Ok(true)
+            }
+            (0, 3) | (0, 8) => {
                 // BPM changes
+                check_channel_data(data)?;
                 let mut iter = 0;
                 while iter < data.len() {
                     let bpm_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
                     let bpm_value: f32 = if channel == 3 {
                         // Channel 3; parse hexadecimal value directly
-                        f32::from(u16::from_str_radix(&data[iter..iter + 2], 16).unwrap())
+                        let hex = &data[iter..iter + 2];
+                        match u16::from_str_radix(hex, 16) {
+                            Ok(bpm) => f32::from(bpm),
+                            Err(_) => return Err(ParseErrorKind::InvalidBpm(hex.to_string())),
+                        }
                     } else {
                         // Channel 8; get BPM value from mapping
-                        bms_builder
-                            .timeline_builder
-                            .find_bpm(Alphanumeric::from_str(&data[iter..iter + 2]))
+                        let bpm_key = bms_builder.parse_key(&data[iter..iter + 2]);
+                        match bms_builder.timeline_builder.find_bpm(&bpm_key) {
+                            Some(bpm) => bpm,
+                            None => {
+                                if bpm_key.key != 0 {
+                                    bms_builder.warn(WarningKind::UndefinedBpm(
+                                        bms_builder.format_key(&bpm_key),
+                                    ));
+                                }
+                                0_f32
+                            }
+                        }
                     };
                     if (bpm_value - 0_f32).abs() > 0.000_001_f32
                     /* sane float comparison */
@@ -338,16 +859,26 @@ impl BmsLineParser for ObjParser {
                     }
                     iter += 2;
                 }
-                return true;
+                Ok(true)
             }
-            9 => {
+            (0, 9) => {
                 // STOP command
+                check_channel_data(data)?;
                 let mut iter = 0;
                 while iter < data.len() {
                     let stop_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
-                    let stop_val: f32 = bms_builder
-                        .timeline_builder
-                        .find_stop(Alphanumeric::from_str(&data[iter..iter + 2]));
+                    let stop_key = bms_builder.parse_key(&data[iter..iter + 2]);
+                    let stop_val: f32 = match bms_builder.timeline_builder.find_stop(&stop_key) {
+                        Some(stop) => stop,
+                        None => {
+                            if stop_key.key != 0 {
+                                bms_builder.warn(WarningKind::UndefinedStop(
+                                    bms_builder.format_key(&stop_key),
+                                ));
+                            }
+                            0_f32
+                        }
+                    };
                     if stop_val != 0_f32 {
                         bms_builder
                             .timeline_builder
@@ -358,39 +889,143 @@ impl BmsLineParser for ObjParser {
                     }
                     iter += 2;
                 }
-                return true;
+                Ok(true)
             }
-            2 => {
+            (28, 12) => {
+                // SC; scroll speed changes
+                check_channel_data(data)?;
+                let mut iter = 0;
+                while iter < data.len() {
+                    let scroll_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
+                    let scroll_key = bms_builder.parse_key(&data[iter..iter + 2]);
+                    iter += 2;
+
+                    if scroll_key.key == 0 {
+                        continue;
+                    }
+                    match bms_builder.timeline_builder.find_scroll(&scroll_key) {
+                        Some(factor) => {
+                            bms_builder
+                                .timeline_builder
+                                .with_event(TimelineEvent::Scroll {
+                                    measure: scroll_measure,
+                                    factor,
+                                });
+                        }
+                        None => {
+                            bms_builder.warn(WarningKind::UndefinedScroll(
+                                bms_builder.format_key(&scroll_key),
+                            ));
+                        }
+                    }
+                }
+                Ok(true)
+            }
+            (28, 25) => {
+                // SP; speed multiplier keyframes
+                check_channel_data(data)?;
+                let mut iter = 0;
+                while iter < data.len() {
+                    let speed_measure: f32 = measure as f32 + (iter as f32) / data.len() as f32;
+                    let speed_key = bms_builder.parse_key(&data[iter..iter + 2]);
+                    iter += 2;
+
+                    if speed_key.key == 0 {
+                        continue;
+                    }
+                    match bms_builder.timeline_builder.find_speed(&speed_key) {
+                        Some(speed) => {
+                            bms_builder
+                                .timeline_builder
+                                .with_event(TimelineEvent::Speed {
+                                    measure: speed_measure,
+                                    speed,
+                                });
+                        }
+                        None => {
+                            bms_builder.warn(WarningKind::UndefinedSpeed(
+                                bms_builder.format_key(&speed_key),
+                            ));
+                        }
+                    }
+                }
+                Ok(true)
+            }
+            (0, 2) => {
                 // Measure length
+                let length = match f32::from_str(data) {
+                    Ok(length) if length.is_finite() && length > 0_f32 => length,
+                    _ => return Err(ParseErrorKind::InvalidMeasureLength(data.to_string())),
+                };
                 bms_builder
                     .timeline_builder
-                    .with_measure_len(measure, f32::from_str(data).unwrap());
-                return true;
+                    .with_measure_len(measure, length);
+                Ok(true)
             }
-            _ => return false,
+            _ => Ok(false),
         }
-        // let result = self.parse_line(line);
-        // if result.is_none() {
-        //     return false
-        // }
-
-        // let value = result.unwrap();
-        // let key = u16::from_str_radix(&value.0, 36).unwrap_or(0);//("Invalid key found on BPM header.");
-        // let bpm = f32::from_str(&value.1).expect("Invalid data found on BPM header.");
-        // if key == 0 {
-        //     // Not sure if necessary, but for completeness sake
-        //     bms_builder.with_metadata("BPM".to_string(), value.1);
-        // }
-        // bms_builder.with_bpm(key, bpm);
-        // true
     }
 
-    fn parse_line(&self, _line: &String) -> Option<(String, String)> {
+    fn parse_line(&self, _line: &str) -> Option<(String, String)> {
         // INIMPLEMENTED!!! Bad method.
         Option::None
     }
 }
 
+struct LnObjParser {
+    regex_parser: Regex,
+}
+
+impl LnObjParser {
+    pub fn new() -> LnObjParser {
+        LnObjParser {
+            regex_parser: Regex::new(r"(?i)#LNOBJ (?P<key>.*)")
+                .expect("Could not initialize regex"),
+        }
+    }
+}
+
+impl BmsLineParser for LnObjParser {
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if let Some(key) = bms_builder.parse_definition_key(&value.1) {
+            bms_builder.with_lnobj(key);
+        }
+        Ok(true)
+    }
+
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
+        Option::from(("LNOBJ".to_string(), res["key"].trim().to_string()))
+    }
+}
+
+/// Checks that channel data can be split into two-character objects.
+fn check_channel_data(data: &str) -> Result<(), ParseErrorKind> {
+    if !data.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(ParseErrorKind::InvalidChannelData(data.to_string()));
+    }
+    if !data.len().is_multiple_of(2) {
+        return Err(ParseErrorKind::OddChannelData);
+    }
+    Ok(())
+}
+
+/// Parses a BPM header value, which must be a positive number.
+fn parse_bpm(value: &str) -> Result<f32, ParseErrorKind> {
+    match f32::from_str(value) {
+        Ok(bpm) if bpm.is_finite() && bpm > 0_f32 => Ok(bpm),
+        _ => Err(ParseErrorKind::InvalidBpm(value.to_string())),
+    }
+}
+
 struct StopParser {
     regex_parser: Regex,
 }
@@ -398,35 +1033,1032 @@ struct StopParser {
 impl StopParser {
     pub fn new() -> StopParser {
         StopParser {
-            regex_parser: Regex::new(r"#STOP(?P<key>.{0,2}) (?P<data>.*)")
+            regex_parser: Regex::new(r"(?i)#STOP(?P<key>.{0,2}) (?P<data>.*)")
                 .expect("Could not initialize regex"),
         }
     }
 }
 
 impl BmsLineParser for StopParser {
-    fn parse_line_into_bms(&self, line: &String, bms_builder: &mut BmsBuilder) -> bool {
-        let result = self.parse_line(line);
-        if result.is_none() {
-            return false;
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let key = bms_builder.parse_key(&value.0); //("Invalid key found on STOP header.");
+        let stop_value = match f32::from_str(&value.1) {
+            Ok(stop) if stop.is_finite() && stop >= 0_f32 => stop,
+            _ => return Err(ParseErrorKind::InvalidStop(value.1)),
+        };
+        bms_builder.with_stop(key, stop_value);
+        Ok(true)
+    }
+
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
+        let (key, data): (&str, &str) = (&res["key"], &res["data"]);
+        let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
+        Option::from(keydata)
+    }
+}
+
+struct ScrollParser {
+    regex_parser: Regex,
+}
+
+impl ScrollParser {
+    pub fn new() -> ScrollParser {
+        ScrollParser {
+            regex_parser: Regex::new(r"(?i)#SCROLL(?P<key>.{2}) (?P<data>.*)")
+                .expect("Could not initialize regex"),
         }
+    }
+}
 
-        let value = result.unwrap();
-        let key = Alphanumeric::from_str(&value.0); //("Invalid key found on STOP header.");
-        let stop_value = f32::from_str(&value.1).expect("Invalid data found on STOP header.");
-        bms_builder.with_stop(key, stop_value);
-        true
+impl BmsLineParser for ScrollParser {
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let key = bms_builder.parse_key(&value.0);
+        // Zero and negative factors are allowed; they stop or reverse scrolling
+        let scroll = match f32::from_str(&value.1) {
+            Ok(scroll) if scroll.is_finite() => scroll,
+            _ => return Err(ParseErrorKind::InvalidScroll(value.1)),
+        };
+        bms_builder.with_scroll(key, scroll);
+        Ok(true)
+    }
+
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
+        let (key, data): (&str, &str) = (&res["key"], &res["data"]);
+        let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
+        Option::from(keydata)
+    }
+}
+
+struct SpeedParser {
+    regex_parser: Regex,
+}
+
+impl SpeedParser {
+    pub fn new() -> SpeedParser {
+        SpeedParser {
+            regex_parser: Regex::new(r"(?i)#SPEED(?P<key>.{2}) (?P<data>.*)")
+                .expect("Could not initialize regex"),
+        }
+    }
+}
+
+impl BmsLineParser for SpeedParser {
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let key = bms_builder.parse_key(&value.0);
+        let speed = match f32::from_str(&value.1) {
+            Ok(speed) if speed.is_finite() => speed,
+            _ => return Err(ParseErrorKind::InvalidSpeed(value.1)),
+        };
+        bms_builder.with_speed(key, speed);
+        Ok(true)
+    }
+
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
+        let (key, data): (&str, &str) = (&res["key"], &res["data"]);
+        let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
+        Option::from(keydata)
+    }
+}
+
+struct BaseParser {
+    regex_parser: Regex,
+}
+
+impl BaseParser {
+    pub fn new() -> BaseParser {
+        BaseParser {
+            regex_parser: Regex::new(r"(?i)#BASE (?P<data>.*)")
+                .expect("Could not initialize regex"),
+        }
+    }
+}
+
+impl BmsLineParser for BaseParser {
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        match value.1.as_str() {
+            "36" => bms_builder.with_base(Base::Base36),
+            "62" => bms_builder.with_base(Base::Base62),
+            _ => bms_builder.warn(WarningKind::UnsupportedBase(value.1)),
+        };
+        Ok(true)
+    }
+
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
+        Option::from(("BASE".to_string(), res["data"].trim().to_string()))
+    }
+}
+
+struct ExRankParser {
+    regex_parser: Regex,
+}
+
+impl ExRankParser {
+    pub fn new() -> ExRankParser {
+        ExRankParser {
+            regex_parser: Regex::new(r"(?i)#EXRANK(?P<key>.{2}) (?P<data>.*)")
+                .expect("Could not initialize regex"),
+        }
     }
+}
 
-    fn parse_line(&self, line: &String) -> Option<(String, String)> {
-        let capture_res = self.regex_parser.captures(line);
-        if capture_res.is_none() {
-            return Option::None;
+impl BmsLineParser for ExRankParser {
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let key = bms_builder.parse_key(&value.0);
+        match f32::from_str(&value.1) {
+            Ok(exrank) if exrank.is_finite() && exrank > 0_f32 => {
+                bms_builder.with_exrank(key, exrank);
+            }
+            _ => {
+                bms_builder.warn(WarningKind::InvalidHeaderValue {
+                    header: format!("EXRANK{}", value.0),
+                    value: value.1,
+                });
+            }
         }
+        Ok(true)
+    }
 
-        let res = capture_res.unwrap();
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
         let (key, data): (&str, &str) = (&res["key"], &res["data"]);
         let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
         Option::from(keydata)
     }
 }
+
+struct ArgbParser {
+    regex_parser: Regex,
+}
+
+impl ArgbParser {
+    pub fn new() -> ArgbParser {
+        ArgbParser {
+            regex_parser: Regex::new(r"(?i)#ARGB(?P<key>.{2}) (?P<data>.*)")
+                .expect("Could not initialize regex"),
+        }
+    }
+}
+
+impl BmsLineParser for ArgbParser {
+    fn parse_line_into_bms(
+        &self,
+        line: &str,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let value = match self.parse_line(line) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let key = bms_builder.parse_key(&value.0);
+        // Four comma-separated components from 0 to 255, alpha first
+        let components: Option<Vec<u8>> = value
+            .1
+            .split(',')
+            .map(|component| component.trim().parse().ok())
+            .collect();
+        match components.as_deref() {
+            Some(&[alpha, red, green, blue]) => {
+                bms_builder.with_argb(
+                    key,
+                    Argb {
+                        alpha,
+                        red,
+                        green,
+                        blue,
+                    },
+                );
+            }
+            _ => {
+                bms_builder.warn(WarningKind::InvalidHeaderValue {
+                    header: format!("ARGB{}", value.0),
+                    value: value.1,
+                });
+            }
+        }
+        Ok(true)
+    }
+
+    fn parse_line(&self, line: &str) -> Option<(String, String)> {
+        let res = self.regex_parser.captures(line)?;
+        let (key, data): (&str, &str) = (&res["key"], &res["data"]);
+        let keydata: (String, String) = (String::from(key.trim()), String::from(data.trim()));
+        Option::from(keydata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bms::header::{Difficulty, JudgeRank, LongNoteMode};
+
+    impl BmsParser {
+        fn parse_contents_fixed(&self, bms_contents: &[u8]) -> Result<BMS, ParseError> {
+            self.parse_contents(bms_contents, &mut FixedChoices::default())
+        }
+    }
+
+    /// Returns the number of a base-36 channel
+    fn ch(channel: &str) -> u32 {
+        u32::from_str_radix(channel, 36).unwrap()
+    }
+
+    fn parse_err(contents: &str) -> ParseError {
+        BmsParser::new()
+            .parse_contents_fixed(contents.as_bytes())
+            .expect_err("Chart should not parse")
+    }
+
+    #[test]
+    fn test_parse_valid_chart() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#TITLE Test\n#BPM 150\n#WAV01 kick.wav\n#00111:0101\n")
+            .unwrap();
+        assert_eq!(bms.header.title.as_deref(), Some("Test"));
+        assert_eq!(bms.objects.len(), 2);
+    }
+
+    #[test]
+    fn test_header() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(
+                b"#PLAYER 1\n#GENRE Trance\n#PLAYLEVEL 7\n#RANK 3\n#TOTAL 300.5\n",
+            )
+            .unwrap();
+        assert_eq!(bms.header.player, Some(PlayerMode::Single));
+        assert_eq!(bms.header.genre.as_deref(), Some("Trance"));
+        assert_eq!(bms.header.playlevel, Some(7));
+        assert_eq!(bms.header.rank, Some(JudgeRank::Easy));
+        assert_eq!(bms.header.total, Some(300.5));
+        assert_eq!(bms.header.title, None);
+        assert!(bms.warnings.is_empty());
+
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#PLAYLEVEL ?\n")
+            .unwrap();
+        assert_eq!(bms.header.playlevel, None);
+        assert_eq!(bms.header.raw["PLAYLEVEL"], "?");
+        assert_eq!(
+            bms.warnings[0].kind,
+            WarningKind::InvalidHeaderValue {
+                header: "PLAYLEVEL".to_string(),
+                value: "?".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_exact_headers() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(
+                b"#TITLEFOO x\n#title Song\n#SubTitle [HYPER]\n#PLAYLEVEL 5\n#Player 3\n\
+                  #DIFFICULTY 3\n#BANNER b.png\n#BACKBMP bg.png\n#PREVIEW p.ogg\n\
+                  #LNMODE 2\n#VOLWAV 80\n#DEFEXRANK 120\n#EXRANK01 48\n",
+            )
+            .unwrap();
+        let header = &bms.header;
+        assert_eq!(header.title.as_deref(), Some("Song"));
+        assert_eq!(header.subtitle.as_deref(), Some("[HYPER]"));
+        assert_eq!(header.playlevel, Some(5));
+        assert_eq!(header.player, Some(PlayerMode::Double));
+        assert_eq!(header.difficulty, Some(Difficulty::Hyper));
+        assert_eq!(header.banner.as_deref(), Some("b.png"));
+        assert_eq!(header.backbmp.as_deref(), Some("bg.png"));
+        assert_eq!(header.preview.as_deref(), Some("p.ogg"));
+        assert_eq!(header.lnmode, Some(LongNoteMode::ChargeNote));
+        assert_eq!(header.volwav, Some(80));
+        assert_eq!(header.exrank, Some(120.0));
+        assert_eq!(bms.exranks[&Alphanumeric::from_int(1)], 48.0);
+        assert_eq!(
+            bms.warnings
+                .iter()
+                .map(|w| &w.kind)
+                .collect::<Vec<&WarningKind>>(),
+            vec![&WarningKind::UnknownCommand("#TITLEFOO".to_string())]
+        );
+    }
+
+    /// Parses a chart from the mixed-case test corpus, along with the same chart in uppercase.
+    fn parse_corpus(contents: &[u8]) -> (BMS, BMS) {
+        let parser = BmsParser::new();
+        (
+            parser.parse_contents_fixed(contents).unwrap(),
+            parser
+                .parse_contents_fixed(&contents.to_ascii_uppercase())
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_mixed_case_corpus() {
+        for contents in [
+            &include_bytes!("../../tests/charts/lowercase.bms")[..],
+            &include_bytes!("../../tests/charts/mixedcase.bms")[..],
+        ] {
+            let (bms, upper) = parse_corpus(contents);
+            assert!(bms.warnings.is_empty(), "{:?}", bms.warnings);
+            assert_eq!(bms.header.player, Some(PlayerMode::Single));
+            assert!(!bms.objects.is_empty());
+            assert_eq!(format!("{:?}", bms.objects), format!("{:?}", upper.objects));
+            assert_eq!(
+                format!("{:?}", bms.timeline),
+                format!("{:?}", upper.timeline)
+            );
+            let mut keys: Vec<usize> = bms.keysounds.keys().map(|k| k.key).collect();
+            let mut upper_keys: Vec<usize> = upper.keysounds.keys().map(|k| k.key).collect();
+            keys.sort_unstable();
+            upper_keys.sort_unstable();
+            assert_eq!(keys, upper_keys);
+        }
+    }
+
+    #[test]
+    fn test_mixed_case_base62() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(include_bytes!("../../tests/charts/base62.bms"))
+            .unwrap();
+        assert!(bms.warnings.is_empty(), "{:?}", bms.warnings);
+        assert_eq!(bms.keysounds.len(), 2);
+        let keys: Vec<String> = bms
+            .objects
+            .iter()
+            .filter_map(|o| match o.objtype {
+                ObjType::Note(key) => Some(key.as_base62()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, vec!["0a", "0A"]);
+        let bpms: Vec<f32> = bms.timeline.events.iter().map(|e| e.bpm).collect();
+        assert_eq!(bpms, vec![130.0, 180.0, 90.0]);
+    }
+
+    #[test]
+    fn test_scan_header() {
+        let contents = b"#TITLE Scan\n#ARTIST Someone\n#PLAYLEVEL 9\n#BPM 150\n#BPM01 300\n\
+            #LNOBJ ZZ\n#WAV01 a.wav\n#00111:0101\n#00112:01ZZ\n#00151:0100000100000000\n\
+            #00103:3C\n#00208:01\n#00216:01\n#00161:01\n#00261:01\n";
+        let scan = BmsParser::new().scan_header_contents(contents).unwrap();
+        assert_eq!(scan.header.title.as_deref(), Some("Scan"));
+        assert_eq!(scan.header.artist.as_deref(), Some("Someone"));
+        assert_eq!(scan.header.playlevel, Some(9));
+        assert_eq!((scan.min_bpm, scan.max_bpm), (60.0, 300.0));
+        assert_eq!(scan.player, PlayerMode::Double);
+
+        // The note count agrees with a full parse
+        let bms = BmsParser::new().parse_contents_fixed(contents).unwrap();
+        let played = bms
+            .objects
+            .iter()
+            .filter(|o| matches!(o.objtype, ObjType::Note(_) | ObjType::LongNote { .. }))
+            .count();
+        assert_eq!(scan.note_count, played);
+        assert_eq!(scan.note_count, 6);
+    }
+
+    #[test]
+    fn test_scan_header_start_bpm() {
+        // A BPM change at the very start replaces the default base BPM of 130
+        let scan = BmsParser::new()
+            .scan_header_contents(b"#00003:96\n#00111:01\n")
+            .unwrap();
+        assert_eq!((scan.min_bpm, scan.max_bpm), (150.0, 150.0));
+        let scan = BmsParser::new()
+            .scan_header_contents(b"#BPM01 90\n#00008:01\n#00111:01\n")
+            .unwrap();
+        assert_eq!((scan.min_bpm, scan.max_bpm), (90.0, 90.0));
+
+        // Anywhere later, the base BPM is played first
+        let scan = BmsParser::new()
+            .scan_header_contents(b"#00003:0096\n#00111:01\n")
+            .unwrap();
+        assert_eq!((scan.min_bpm, scan.max_bpm), (130.0, 150.0));
+    }
+
+    #[test]
+    fn test_scan_header_lntype2() {
+        let contents = b"#LNTYPE 2\n#PLAYER 1\n#00151:01010000\n#00152:0101\n#00252:0101\n";
+        let scan = BmsParser::new().scan_header_contents(contents).unwrap();
+        let bms = BmsParser::new().parse_contents_fixed(contents).unwrap();
+        assert_eq!(scan.note_count, bms.objects.len());
+        assert_eq!(scan.note_count, 2);
+        // A zero-padded type is the same type
+        let contents = b"#LNTYPE 02\n#00151:01010000\n#00152:0101\n#00252:0101\n";
+        let scan = BmsParser::new().scan_header_contents(contents).unwrap();
+        let bms = BmsParser::new().parse_contents_fixed(contents).unwrap();
+        assert_eq!((scan.note_count, bms.objects.len()), (2, 2));
+        assert_eq!(scan.player, PlayerMode::Single);
+        assert_eq!((scan.min_bpm, scan.max_bpm), (130.0, 130.0));
+    }
+
+    #[test]
+    fn test_parse_inputs() {
+        let text = "#TITLE テスト\n#WAV01 kick.wav\n#00111:01\n";
+        let mut parser = BmsParser::new();
+        parser.with_path("charts/song/chart.bms");
+        for bms in [
+            parser.parse_str(text).unwrap(),
+            parser.parse_bytes(text.as_bytes()).unwrap(),
+            parser.parse_reader(text.as_bytes()).unwrap(),
+        ] {
+            assert_eq!(bms.header.title.as_deref(), Some("テスト"));
+            assert_eq!(bms.objects.len(), 1);
+            assert_eq!(
+                bms.resolve_asset(&bms.keysounds[&Alphanumeric::from_int(1)]),
+                Path::new("charts/song/kick.wav")
+            );
+        }
+
+        // Without a path, assets are relative to the working directory
+        let bms = BmsParser::new().parse_str(text).unwrap();
+        assert_eq!(bms.resolve_asset("kick.wav"), Path::new("kick.wav"));
+    }
+
+    #[test]
+    fn test_parse_document() {
+        let parser = BmsParser::new();
+        let contents = b"; made by hand\r\n#WAV01 a.wav\r\n#00111:01\r\n#00112:0101\r\n";
+        let mut document = parser.read_document(contents).unwrap();
+        let line = document.find_channel_lines(1, "11")[0];
+        document.set_line(line, "#00111:0001");
+        let bms = parser.parse_document(&document).unwrap();
+        let measures: Vec<f32> = bms.objects.iter().map(|o| o.measure).collect();
+        assert_eq!(measures, vec![1.0, 1.5, 1.5]);
+        assert_eq!(
+            document.encode(),
+            b"; made by hand\r\n#WAV01 a.wav\r\n#00111:0001\r\n#00112:0101\r\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(
+                b"#WAVZZZ a.wav\n#WAVZZZ b.wav\n#BMP150 x.png\n#LNOBJ 1A2\n#WAVZZ c.wav\n\
+                  #WAVZZ d.wav\n",
+            )
+            .unwrap();
+        let warnings: Vec<(usize, &WarningKind)> =
+            bms.warnings.iter().map(|w| (w.line, &w.kind)).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                (1, &WarningKind::InvalidKey("ZZZ".to_string())),
+                (2, &WarningKind::InvalidKey("ZZZ".to_string())),
+                (3, &WarningKind::InvalidKey("150".to_string())),
+                (4, &WarningKind::InvalidKey("1A2".to_string())),
+                (6, &WarningKind::DuplicateKeysound("ZZ".to_string())),
+            ]
+        );
+        assert_eq!(bms.keysounds.len(), 1);
+        assert!(bms.bga_layers.is_empty());
+        assert!(bms.lnobjs.is_empty());
+    }
+
+    #[test]
+    fn test_warnings() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(
+                b"#WAV01 a.wav\n#WAV01 b.wav\n#BPM01 180\n#00108:0102\n#00109:03\n#FOO bar\n",
+            )
+            .unwrap();
+        let warnings: Vec<(usize, &WarningKind)> =
+            bms.warnings.iter().map(|w| (w.line, &w.kind)).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                (2, &WarningKind::DuplicateKeysound("01".to_string())),
+                (4, &WarningKind::UndefinedBpm("02".to_string())),
+                (5, &WarningKind::UndefinedStop("03".to_string())),
+                (6, &WarningKind::UnknownCommand("#FOO".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detect_shift_jis() {
+        // "#TITLE テスト" in Shift_JIS
+        let contents = b"#TITLE \x83\x65\x83\x58\x83\x67\n";
+        let bms = BmsParser::new().parse_contents_fixed(contents).unwrap();
+        assert_eq!(bms.encoding, BmsEncoding::ShiftJis);
+        assert_eq!(bms.header.title.as_deref(), Some("テスト"));
+    }
+
+    #[test]
+    fn test_detect_utf8_bom() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed("\u{feff}#TITLE テスト\n".as_bytes())
+            .unwrap();
+        assert_eq!(bms.encoding, BmsEncoding::Utf8);
+        assert_eq!(bms.header.title.as_deref(), Some("テスト"));
+    }
+
+    #[test]
+    fn test_forced_encoding() {
+        // "#TITLE 한국" in EUC-KR, which is also valid Shift_JIS
+        let contents = b"#TITLE \xC7\xD1\xB1\xB9\n";
+        let mut parser = BmsParser::new();
+        parser.with_encoding(BmsEncoding::EucKr);
+        let bms = parser.parse_contents_fixed(contents).unwrap();
+        assert_eq!(bms.encoding, BmsEncoding::EucKr);
+        assert_eq!(bms.header.title.as_deref(), Some("한국"));
+    }
+
+    const RANDOM_CHART: &str = "#WAV01 a.wav
+#RANDOM 2
+#IF 1
+#00111:01
+#ELSE
+#00112:01
+#RANDOM 3
+#IF 3
+#00113:01
+#ENDIF
+#ENDRANDOM
+#ENDIF
+#SWITCH 2
+#CASE 1
+#00114:01
+#CASE 2
+#00115:01
+#SKIP
+#DEF
+#00116:01
+#ENDSW
+";
+
+    fn random_channels(choices: Vec<u32>) -> (Vec<u32>, Vec<u32>) {
+        let bms = BmsParser::new()
+            .parse_contents(RANDOM_CHART.as_bytes(), &mut FixedChoices::new(choices))
+            .unwrap();
+        assert!(bms.warnings.is_empty());
+        let channels = bms.objects.iter().map(|o| o.channel).collect();
+        (channels, bms.random_choices)
+    }
+
+    #[test]
+    fn test_random_branches() {
+        assert_eq!(
+            random_channels(vec![1, 1]),
+            (vec![ch("11"), ch("14"), ch("15")], vec![1, 1])
+        );
+        assert_eq!(
+            random_channels(vec![2, 3, 2]),
+            (vec![ch("12"), ch("13"), ch("15")], vec![2, 3, 2])
+        );
+        // The nested #RANDOM isn't evaluated when its branch isn't taken
+        assert_eq!(
+            random_channels(vec![1, 2]),
+            (vec![ch("11"), ch("15")], vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn test_random_replay() {
+        let mut rng = XorShiftRng::new(1234);
+        let bms = BmsParser::new()
+            .parse_contents(RANDOM_CHART.as_bytes(), &mut rng)
+            .unwrap();
+        let replayed = BmsParser::new()
+            .parse_contents(
+                RANDOM_CHART.as_bytes(),
+                &mut FixedChoices::new(bms.random_choices.clone()),
+            )
+            .unwrap();
+        let measures = |bms: &BMS| bms.objects.iter().map(|o| o.channel).collect::<Vec<_>>();
+        assert_eq!(measures(&bms), measures(&replayed));
+    }
+
+    #[test]
+    fn test_parse_all_random() {
+        let parse_all = |max_variants| {
+            let variants = BmsParser::new()
+                .parse_all_random_contents(RANDOM_CHART.as_bytes(), max_variants)
+                .unwrap();
+            let choices: Vec<Vec<u32>> = variants.choices().iter().map(|c| c.to_vec()).collect();
+            (choices, variants.truncated)
+        };
+        let (choices, truncated) = parse_all(100);
+        assert!(!truncated);
+        assert_eq!(
+            choices,
+            vec![
+                vec![1, 1],
+                vec![1, 2],
+                vec![2, 1, 1],
+                vec![2, 1, 2],
+                vec![2, 2, 1],
+                vec![2, 2, 2],
+                vec![2, 3, 1],
+                vec![2, 3, 2],
+            ]
+        );
+        assert_eq!(parse_all(3), (choices[..3].to_vec(), true));
+    }
+
+    fn long_notes(contents: &str) -> Vec<(u32, f32, f32)> {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(contents.as_bytes())
+            .unwrap();
+        bms.objects
+            .iter()
+            .filter_map(|o| match o.objtype {
+                ObjType::LongNote { end_measure, .. } => Some((o.channel, o.measure, end_measure)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lntype_1() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#BPM 120\n#00151:01000100\n#00251:0101\n#00352:01\n")
+            .unwrap();
+        let long_notes: Vec<(u32, f32, f32, i64, i64)> = bms
+            .objects
+            .iter()
+            .filter_map(|o| match o.objtype {
+                ObjType::LongNote {
+                    end_measure,
+                    end_time,
+                    ..
+                } => Some((o.channel, o.measure, end_measure, o.time, end_time)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            long_notes,
+            vec![
+                (ch("51"), 1.0, 1.5, 2000, 3000),
+                (ch("51"), 2.0, 2.5, 4000, 5000)
+            ]
+        );
+        assert_eq!(
+            bms.warnings[0].kind,
+            WarningKind::UnterminatedLongNote(ch("52"))
+        );
+    }
+
+    #[test]
+    fn test_lntype_2() {
+        assert_eq!(
+            long_notes("#LNTYPE 2\n#00151:01010000\n#00161:0101\n#00261:0102\n#00461:01\n"),
+            vec![
+                (ch("51"), 1.0, 1.5),
+                (ch("61"), 1.0, 2.5),
+                (ch("61"), 2.5, 3.0),
+                (ch("61"), 4.0, 5.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lnobj() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(
+                b"#LNOBJ ZZ\n#00111:0100ZZ00\n#00112:ZZ\n#00211:00ZZ\n#00111:00000200\n",
+            )
+            .unwrap();
+        let objects: Vec<(u32, f32)> = bms.objects.iter().map(|o| (o.channel, o.measure)).collect();
+        assert_eq!(objects, vec![(ch("11"), 1.0), (ch("11"), 1.5)]);
+        assert!(matches!(
+            bms.objects[0].objtype,
+            ObjType::LongNote { end_measure, .. } if end_measure == 1.5
+        ));
+        assert!(matches!(
+            bms.objects[1].objtype,
+            ObjType::LongNote { end_measure, .. } if end_measure == 2.5
+        ));
+        assert_eq!(
+            bms.warnings.iter().map(|w| &w.kind).collect::<Vec<_>>(),
+            vec![&WarningKind::OrphanLnObj {
+                channel: ch("12"),
+                measure: 1.0
+            }]
+        );
+        assert_eq!(bms.warnings[0].line, 3);
+        assert_eq!(bms.warnings[0].text, "#00112:ZZ");
+    }
+
+    #[test]
+    fn test_player_sides() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#00101:01\n#00111:01\n#00117:01\n#00121:01\n#00129:01\n")
+            .unwrap();
+        let objects: Vec<(u32, Option<PlayerSide>)> =
+            bms.objects.iter().map(|o| (o.channel, o.side)).collect();
+        assert_eq!(
+            objects,
+            vec![
+                (ch("01"), None),
+                (ch("11"), Some(PlayerSide::Player1)),
+                (ch("17"), Some(PlayerSide::Player1)),
+                (ch("21"), Some(PlayerSide::Player2)),
+                (ch("29"), Some(PlayerSide::Player2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invisible_notes() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#00131:0A\n#00149:0B\n")
+            .unwrap();
+        assert!(matches!(bms.objects[0].objtype, ObjType::Invisible(k) if k.key == 10));
+        assert_eq!(bms.objects[0].side, Some(PlayerSide::Player1));
+        assert!(matches!(bms.objects[1].objtype, ObjType::Invisible(k) if k.key == 11));
+        assert_eq!(bms.objects[1].side, Some(PlayerSide::Player2));
+    }
+
+    #[test]
+    fn test_landmines() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#001D1:000A\n#001E9:ZZ\n")
+            .unwrap();
+        let objects: Vec<(u32, f32, Option<PlayerSide>)> = bms
+            .objects
+            .iter()
+            .map(|o| match o.objtype {
+                ObjType::Mine { damage } => (o.channel, o.measure, o.side, damage),
+                _ => panic!("Expected a landmine"),
+            })
+            .map(|(channel, measure, side, damage)| {
+                assert_eq!(damage, if channel == ch("D1") { 10 } else { 1295 });
+                (channel, measure, side)
+            })
+            .collect();
+        assert_eq!(
+            objects,
+            vec![
+                (ch("E9"), 1.0, Some(PlayerSide::Player2)),
+                (ch("D1"), 1.5, Some(PlayerSide::Player1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bga_layers() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(
+                b"#BMP01 a.bmp\n#00104:01\n#00106:02\n#00107:03\n#0010A:04\n#0010C:80\n",
+            )
+            .unwrap();
+        let layers: Vec<(BgaLayer, usize)> = bms
+            .objects
+            .iter()
+            .filter_map(|o| match o.objtype {
+                ObjType::BGA { layer, key } => Some((layer, key.key)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            layers,
+            vec![
+                (BgaLayer::Base, 1),
+                (BgaLayer::Poor, 2),
+                (BgaLayer::Layer, 3),
+                (BgaLayer::Layer2, 4),
+            ]
+        );
+        assert!(matches!(
+            bms.objects[4].objtype,
+            ObjType::BGAOpacity {
+                layer: BgaLayer::Layer,
+                opacity: 0x80
+            }
+        ));
+
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#ARGB01 255,0,128,255\n#ARGB02 1,2,3\n#0010J:01\n")
+            .unwrap();
+        assert_eq!(
+            bms.argbs[&Alphanumeric::from_int(1)],
+            Argb {
+                alpha: 255,
+                red: 0,
+                green: 128,
+                blue: 255
+            }
+        );
+        assert!(matches!(
+            bms.objects[0].objtype,
+            ObjType::BGAArgb {
+                layer: BgaLayer::Poor,
+                key: Alphanumeric { key: 1 }
+            }
+        ));
+        assert!(matches!(
+            bms.warnings[0].kind,
+            WarningKind::InvalidHeaderValue { ref header, .. } if header == "ARGB02"
+        ));
+    }
+
+    #[test]
+    fn test_exbpm() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#BPM 120\n#EXBPM01 150.5\n#BPM02 90\n#00108:0102\n")
+            .unwrap();
+        let bpms: Vec<f32> = bms.timeline.events.iter().map(|e| e.bpm).collect();
+        assert_eq!(bpms, vec![120.0, 150.5, 90.0]);
+
+        // Redefining a key with the same spelling is allowed, but not with the other one
+        assert!(BmsParser::new()
+            .parse_contents_fixed(b"#BPM01 150\n#EXBPM01 150\n#BPM02 90\n#BPM02 100\n")
+            .is_ok());
+        let e = parse_err("#BPM01 150\n#EXBPM01 151\n");
+        assert_eq!(e.line, 2);
+        assert!(matches!(e.kind, ParseErrorKind::ConflictingBpm(ref k) if k == "01"));
+    }
+
+    #[test]
+    fn test_scroll() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#BPM 120\n#SCROLL01 0.5\n#SCROLL02 2\n#001SC:0102\n#003SC:03\n")
+            .unwrap();
+        let timeline = &bms.timeline;
+        // Times are unaffected by scroll changes
+        assert_eq!(timeline.time_from_measure(2.0), 4000);
+        assert_eq!(timeline.time_from_measure(4.0), 8000);
+        // Positions move at half speed in measure 1.0-1.5 and at double speed from 1.5
+        assert_eq!(timeline.pos_from_measure(1.0, 1.0), 120.0);
+        assert_eq!(timeline.pos_from_measure(1.5, 1.0), 150.0);
+        assert_eq!(timeline.pos_from_measure(2.0, 1.0), 270.0);
+        assert_eq!(timeline.pos_from_measure(4.0, 1.0), 750.0);
+        assert_eq!(
+            bms.warnings[0].kind,
+            WarningKind::UndefinedScroll("03".to_string())
+        );
+    }
+
+    #[test]
+    fn test_speed() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#BPM 120\n#SPEED01 1\n#SPEED02 3\n#001SP:01\n#002SP:02\n")
+            .unwrap();
+        let timeline = &bms.timeline;
+        assert_eq!(timeline.speeds.len(), 2);
+        assert_eq!(timeline.speed_at(0), 1.0);
+        assert_eq!(timeline.speed_at(2000), 1.0);
+        assert_eq!(timeline.speed_at(3000), 2.0);
+        assert_eq!(timeline.speed_at(4000), 3.0);
+        assert_eq!(timeline.speed_at(10000), 3.0);
+        // Speed keyframes don't affect the timing
+        assert_eq!(timeline.events.len(), 1);
+    }
+
+    #[test]
+    fn test_base62() {
+        let bms = BmsParser::new()
+            .parse_contents_fixed(
+                b"#BASE 62\n#WAVzz low.wav\n#WAVZZ up.wav\n#BPMaB 150\n#00111:zzZZ\n#00108:aB\n",
+            )
+            .unwrap();
+        assert_eq!(bms.base, Base::Base62);
+        let low = Alphanumeric::from_str_base("zz", Base::Base62);
+        let up = Alphanumeric::from_str_base("ZZ", Base::Base62);
+        assert_eq!(bms.keysounds[&low], "low.wav");
+        assert_eq!(bms.keysounds[&up], "up.wav");
+        assert!(matches!(bms.objects[0].objtype, ObjType::Note(k) if k == low));
+        assert!(matches!(bms.objects[1].objtype, ObjType::Note(k) if k == up));
+        assert_eq!(bms.timeline.events[1].bpm, 150.0);
+        assert!(bms.warnings.is_empty());
+
+        // Without #BASE 62, keys are case-insensitive
+        let bms = BmsParser::new()
+            .parse_contents_fixed(b"#WAVzz low.wav\n#WAVZZ up.wav\n")
+            .unwrap();
+        assert_eq!(bms.keysounds.len(), 1);
+        assert_eq!(
+            bms.warnings[0].kind,
+            WarningKind::DuplicateKeysound("ZZ".to_string())
+        );
+    }
+
+    #[test]
+    fn test_invalid_bpm() {
+        let e = parse_err("#TITLE Test\n#BPM fast\n");
+        assert_eq!(e.line, 2);
+        assert_eq!(e.text, "#BPM fast");
+        assert!(matches!(e.kind, ParseErrorKind::InvalidBpm(_)));
+    }
+
+    #[test]
+    fn test_invalid_stop() {
+        let e = parse_err("#STOP01 -5\n");
+        assert!(matches!(e.kind, ParseErrorKind::InvalidStop(_)));
+    }
+
+    #[test]
+    fn test_invalid_measure_length() {
+        let e = parse_err("#00102:abc\n");
+        assert!(matches!(e.kind, ParseErrorKind::InvalidMeasureLength(_)));
+    }
+
+    #[test]
+    fn test_odd_channel_data() {
+        let e = parse_err("#00111:010\n");
+        assert_eq!(e.line, 1);
+        assert!(matches!(e.kind, ParseErrorKind::OddChannelData));
+    }
+
+    #[test]
+    fn test_pms_layout() {
+        const PMS: &[u8] = b"#00111:01\n#00122:01\n#00165:0101\n";
+        let bms = BmsParser::new().parse_contents_fixed(PMS).unwrap();
+        assert_eq!(bms.layout, LaneLayout::PopN);
+        assert!(bms
+            .objects
+            .iter()
+            .all(|o| o.side == Some(PlayerSide::Player1)));
+        let lanes: Vec<Option<u32>> = bms
+            .objects
+            .iter()
+            .map(|o| bms.layout.lane(o.channel))
+            .collect();
+        assert_eq!(lanes, vec![Some(1), Some(6), Some(9)]);
+        assert_eq!(bms.layout.judge_windows().good, 117);
+        let scan = BmsParser::new().scan_header_contents(PMS).unwrap();
+        assert_eq!(scan.layout, LaneLayout::PopN);
+        assert_eq!(scan.player, PlayerMode::Single);
+
+        // Double play and beatmania-only lanes are never pop'n
+        for contents in [&b"#PLAYER 3\n#00122:01\n"[..], b"#00116:01\n#00122:01\n"] {
+            let bms = BmsParser::new().parse_contents_fixed(contents).unwrap();
+            assert_eq!(bms.layout, LaneLayout::Beat);
+            let scan = BmsParser::new().scan_header_contents(contents).unwrap();
+            assert_eq!(scan.layout, LaneLayout::Beat);
+            assert_eq!(scan.player, PlayerMode::Double);
+        }
+
+        // Invisible notes and landmines count the same in both
+        for (contents, layout) in [
+            (&b"#00111:01\n#00122:01\n#00137:01\n"[..], LaneLayout::Beat),
+            (b"#00111:01\n#001E2:01\n", LaneLayout::PopN),
+        ] {
+            let bms = BmsParser::new().parse_contents_fixed(contents).unwrap();
+            let scan = BmsParser::new().scan_header_contents(contents).unwrap();
+            assert_eq!((bms.layout, scan.layout), (layout, layout));
+        }
+
+        // The extension is enough on its own
+        let mut parser = BmsParser::new();
+        parser.with_path("songs/song.PMS");
+        let bms = parser.parse_str("#00111:01\n").unwrap();
+        assert_eq!(bms.layout, LaneLayout::PopN);
+        let bms = BmsParser::new().parse_str("#00111:01\n").unwrap();
+        assert_eq!(bms.layout, LaneLayout::Beat);
+    }
+
+    #[test]
+    fn test_indented_commands() {
+        let contents = b" #WAV01 a.wav\n\t#00111:01\n";
+        let bms = BmsParser::new().parse_contents_fixed(contents).unwrap();
+        assert_eq!(bms.keysounds.len(), 1);
+        assert_eq!(bms.objects.len(), 1);
+        assert!(bms.warnings.is_empty());
+        let scan = BmsParser::new().scan_header_contents(contents).unwrap();
+        assert_eq!(scan.note_count, 1);
+    }
+}
@@ -0,0 +1,372 @@
+/// Evaluation of the `#RANDOM`/`#IF` and `#SWITCH`/`#CASE` control-flow commands.
+///
+/// Branch selection is delegated to a `RandomSource`, so callers can either supply their own
+/// random number generator or replay a fixed list of choices. Every number drawn while parsing
+/// is recorded in `BMS::random_choices`; feeding that list back through `FixedChoices`
+/// reproduces the exact same chart.
+use crate::bms::{
+    error::{ParseErrorKind, WarningKind},
+    format::BmsBuilder,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of branch choices for `#RANDOM` and `#SWITCH`.
+pub trait RandomSource {
+    /// Returns a number between 1 and `max`, inclusive.
+    fn generate(&mut self, max: u32) -> u32;
+}
+
+/// Replays a fixed list of branch choices in order.
+/// Once the list runs out, or if a choice is out of range, 1 is chosen instead.
+#[derive(Debug, Default)]
+pub struct FixedChoices {
+    choices: Vec<u32>,
+    index: usize,
+}
+
+impl FixedChoices {
+    pub fn new(choices: Vec<u32>) -> FixedChoices {
+        FixedChoices { choices, index: 0 }
+    }
+}
+
+impl RandomSource for FixedChoices {
+    fn generate(&mut self, max: u32) -> u32 {
+        let choice = self.choices.get(self.index).copied().unwrap_or(1);
+        self.index += 1;
+        if choice >= 1 && choice <= max {
+            choice
+        } else {
+            1
+        }
+    }
+}
+
+/// A small xorshift pseudo-random number generator.
+#[derive(Debug)]
+pub struct XorShiftRng {
+    state: u64,
+}
+
+impl XorShiftRng {
+    pub fn new(seed: u64) -> XorShiftRng {
+        // A zero state would only ever produce zeroes
+        XorShiftRng {
+            state: if seed == 0 {
+                0x2545_F491_4F6C_DD1D
+            } else {
+                seed
+            },
+        }
+    }
+
+    /// Creates a generator seeded from the current time.
+    pub fn from_time() -> XorShiftRng {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        XorShiftRng::new(seed)
+    }
+}
+
+impl RandomSource for XorShiftRng {
+    fn generate(&mut self, max: u32) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % u64::from(max)) as u32 + 1
+    }
+}
+
+/// Walks through every combination of branch choices, one parse at a time.
+///
+/// Each parse replays a prefix of choices and picks 1 for every block beyond it, recording how
+/// many branches each block had. The next prefix is found by incrementing the last choice that
+/// hasn't reached its maximum, like an odometer. Since nested blocks are only drawn when their
+/// branch is taken, this visits exactly the reachable combinations.
+#[derive(Debug, Default)]
+pub(crate) struct ChoiceEnumerator {
+    prefix: Vec<u32>,
+    choices: Vec<u32>,
+    maxes: Vec<u32>,
+}
+
+impl ChoiceEnumerator {
+    pub fn new() -> ChoiceEnumerator {
+        ChoiceEnumerator::default()
+    }
+
+    /// Moves on to the next combination after a parse. Returns false once every combination
+    /// has been visited.
+    pub fn advance(&mut self) -> bool {
+        let next = self
+            .choices
+            .iter()
+            .zip(self.maxes.iter())
+            .rposition(|(choice, max)| choice < max);
+        self.prefix = match next {
+            Some(i) => {
+                let mut prefix = self.choices[..=i].to_vec();
+                prefix[i] += 1;
+                prefix
+            }
+            None => return false,
+        };
+        self.choices.clear();
+        self.maxes.clear();
+        true
+    }
+}
+
+impl RandomSource for ChoiceEnumerator {
+    fn generate(&mut self, max: u32) -> u32 {
+        let choice = self.prefix.get(self.choices.len()).copied().unwrap_or(1);
+        self.choices.push(choice);
+        self.maxes.push(max);
+        choice
+    }
+}
+
+/// A single level of control-flow nesting.
+enum Frame {
+    /// A `#RANDOM` block, along with its (possibly nested) `#IF` blocks.
+    Random { value: u32, branches: Vec<Branch> },
+    /// A `#SWITCH` block.
+    Switch { value: u32, case: Case },
+}
+
+/// The state of an `#IF`/`#ELSEIF`/`#ELSE` chain.
+struct Branch {
+    active: bool,
+    // Whether any branch in the chain has been taken already
+    taken: bool,
+}
+
+/// The state of the `#CASE` labels in a `#SWITCH` block.
+struct Case {
+    active: bool,
+    matched: bool,
+    skipped: bool,
+}
+
+/// Tracks control-flow nesting while the file is parsed line by line, and decides which lines
+/// are active.
+#[derive(Default)]
+pub(crate) struct ControlFlow {
+    frames: Vec<Frame>,
+}
+
+impl ControlFlow {
+    pub fn new() -> ControlFlow {
+        ControlFlow { frames: Vec::new() }
+    }
+
+    /// Returns whether lines at the current position should be parsed.
+    pub fn is_active(&self) -> bool {
+        self.frames.iter().all(|frame| match frame {
+            Frame::Random { branches, .. } => branches.iter().all(|b| b.active),
+            Frame::Switch { case, .. } => case.active,
+        })
+    }
+
+    /// Processes the line if it is a control-flow command. Returns `Ok(false)` if it isn't one.
+    ///
+    /// Random numbers are only drawn for blocks that are active, so the sequence of numbers
+    /// drawn only depends on the branches that were actually taken.
+    pub fn process(
+        &mut self,
+        line: &str,
+        rng: &mut dyn RandomSource,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<bool, ParseErrorKind> {
+        let mut tokens = line.split_whitespace();
+        let command = match tokens.next() {
+            Some(command) => match command.strip_prefix('#') {
+                Some(command) => command.to_ascii_uppercase(),
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        let arg = tokens.next();
+
+        match command.as_str() {
+            "RANDOM" | "SETRANDOM" => {
+                let value = self.block_value(&command, arg, rng, bms_builder)?;
+                // A #RANDOM directly following another one at the same level replaces it
+                if let Some(Frame::Random { branches, .. }) = self.frames.last() {
+                    if branches.is_empty() {
+                        self.frames.pop();
+                    }
+                }
+                self.frames.push(Frame::Random {
+                    value,
+                    branches: Vec::new(),
+                });
+            }
+            "ENDRANDOM" => {
+                if !self.pop_through(|f| matches!(f, Frame::Random { .. })) {
+                    bms_builder.warn(WarningKind::UnmatchedControlFlow(command));
+                }
+            }
+            "IF" | "ELSEIF" | "ELSE" | "ENDIF" | "END" => {
+                if command == "END" && !arg.is_some_and(|a| a.eq_ignore_ascii_case("IF")) {
+                    return Ok(false);
+                }
+                let arg = if command == "IF" || command == "ELSEIF" {
+                    Some(parse_arg(arg)?)
+                } else {
+                    None
+                };
+                self.process_if(&command, arg, bms_builder);
+            }
+            "SWITCH" | "SETSWITCH" => {
+                let value = self.block_value(&command, arg, rng, bms_builder)?;
+                self.frames.push(Frame::Switch {
+                    value,
+                    case: Case {
+                        active: false,
+                        matched: false,
+                        skipped: false,
+                    },
+                });
+            }
+            "CASE" | "DEF" | "SKIP" => {
+                let label = if command == "CASE" {
+                    Some(parse_arg(arg)?)
+                } else {
+                    None
+                };
+                match self.frames.last_mut() {
+                    Some(Frame::Switch { value, case }) => match label {
+                        // Cases fall through until a #SKIP
+                        Some(label) => {
+                            if !case.skipped && (case.active || label == *value) {
+                                case.active = true;
+                                case.matched = true;
+                            }
+                        }
+                        None if command == "DEF" => {
+                            if !case.skipped && !case.matched {
+                                case.active = true;
+                                case.matched = true;
+                            }
+                        }
+                        None => {
+                            if case.active {
+                                case.active = false;
+                                case.skipped = true;
+                            }
+                        }
+                    },
+                    _ => {
+                        bms_builder.warn(WarningKind::UnmatchedControlFlow(command));
+                    }
+                }
+            }
+            "ENDSW" => {
+                if !self.pop_through(|f| matches!(f, Frame::Switch { .. })) {
+                    bms_builder.warn(WarningKind::UnmatchedControlFlow(command));
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Finds the value of a new `#RANDOM`/`#SWITCH` block, drawing a random number if needed.
+    fn block_value(
+        &self,
+        command: &str,
+        arg: Option<&str>,
+        rng: &mut dyn RandomSource,
+        bms_builder: &mut BmsBuilder,
+    ) -> Result<u32, ParseErrorKind> {
+        let arg = parse_arg(arg)?;
+        if !self.is_active() {
+            // Inactive blocks never match any branch, as branch labels start at 1
+            Ok(0)
+        } else if command.starts_with("SET") {
+            Ok(arg)
+        } else if arg == 0 {
+            Err(ParseErrorKind::InvalidRandom(arg.to_string()))
+        } else {
+            let value = rng.generate(arg);
+            bms_builder.with_random_choice(value);
+            Ok(value)
+        }
+    }
+
+    fn process_if(&mut self, command: &str, arg: Option<u32>, bms_builder: &mut BmsBuilder) {
+        if command != "IF" {
+            // Any #RANDOM blocks opened inside the current #IF are implicitly closed
+            while let Some(Frame::Random { branches, .. }) = self.frames.last() {
+                if !branches.is_empty() {
+                    break;
+                }
+                self.frames.pop();
+            }
+        }
+
+        let (value, branches) = match self.frames.last_mut() {
+            Some(Frame::Random { value, branches }) => (*value, branches),
+            _ => {
+                if command == "IF" {
+                    // An #IF without a #RANDOM never matches
+                    self.frames.push(Frame::Random {
+                        value: 0,
+                        branches: vec![Branch {
+                            active: false,
+                            taken: true,
+                        }],
+                    });
+                } else {
+                    bms_builder.warn(WarningKind::UnmatchedControlFlow(command.to_string()));
+                }
+                return;
+            }
+        };
+
+        match (command, branches.last_mut()) {
+            ("IF", _) => {
+                let active = arg == Some(value);
+                branches.push(Branch {
+                    active,
+                    taken: active,
+                });
+            }
+            ("ELSEIF", Some(branch)) => {
+                branch.active = !branch.taken && arg == Some(value);
+                branch.taken |= branch.active;
+            }
+            ("ELSE", Some(branch)) => {
+                branch.active = !branch.taken;
+                branch.taken = true;
+            }
+            (_, Some(_)) => {
+                branches.pop();
+            }
+            (_, None) => {
+                bms_builder.warn(WarningKind::UnmatchedControlFlow(command.to_string()));
+            }
+        }
+    }
+
+    /// Pops frames up to and including the last one matching `pred`. Returns false and leaves
+    /// the frames untouched if no frame matches.
+    fn pop_through(&mut self, pred: impl Fn(&Frame) -> bool) -> bool {
+        match self.frames.iter().rposition(pred) {
+            Some(i) => {
+                self.frames.truncate(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn parse_arg(arg: Option<&str>) -> Result<u32, ParseErrorKind> {
+    let arg = arg.unwrap_or("");
+    arg.parse::<u32>()
+        .map_err(|_| ParseErrorKind::InvalidRandom(arg.to_string()))
+}
@@ -33,12 +33,28 @@ pub struct Event {
     pub bpm: f32,
     /// The length of a measure in the current event.
     pub length: f32,
+    /// The scroll speed factor of the event. It only affects positions, not times.
+    pub scroll: f32,
+}
+
+/// A keyframe of the note speed multiplier. The multiplier is interpolated linearly between
+/// keyframes.
+#[derive(Debug)]
+pub struct SpeedKeyframe {
+    /// The time in milliseconds of the keyframe.
+    pub time: i64,
+    /// The measure number of the keyframe.
+    pub measure: f32,
+    /// The speed multiplier at the keyframe.
+    pub speed: f32,
 }
 
 /// The timeline struct, which contains Events in chronological order.
 #[derive(Debug, Default)]
 pub struct Timeline {
     pub events: Vec<Event>,
+    /// Speed multiplier keyframes in chronological order.
+    pub speeds: Vec<SpeedKeyframe>,
 }
 
 impl Timeline {
@@ -49,8 +65,8 @@ impl Timeline {
         self.events.last().expect("Empty timeline")
     }
 
-    /// Add a new event if provided as measure and length
-    pub fn add_event(&mut self, measure: f32, bpm: f32, length: f32) {
+    /// Add a new event if provided as measure, length and scroll factor
+    pub fn add_event(&mut self, measure: f32, bpm: f32, length: f32, scroll: f32) {
         // If there are no events, just add the current event
         if self.events.is_empty() {
             self.events.push(Event {
@@ -59,17 +75,19 @@ impl Timeline {
                 pos: 0.0,
                 bpm,
                 length,
+                scroll,
             });
         } else {
-            // Only add if the event is an actual update (new BPM or new length)
+            // Only add if the event is an actual update (new BPM, length or scroll factor)
             let &Event {
                 mut time,
                 measure: old_measure,
                 bpm: old_bpm,
                 length: old_length,
+                scroll: old_scroll,
                 ..
             } = self.last_event();
-            if old_bpm != bpm || old_length != length {
+            if old_bpm != bpm || old_length != length || old_scroll != scroll {
                 // Calculate new time
                 time += ((measure - old_measure) * (240_000f32 / old_bpm) * old_length) as i64;
                 self.events.push(Event {
@@ -78,6 +96,7 @@ impl Timeline {
                     pos: 0.0,
                     bpm,
                     length,
+                    scroll,
                 });
             }
         }
@@ -93,6 +112,7 @@ impl Timeline {
             pos,
             bpm,
             length,
+            scroll,
         } = self.last_event();
         if old_measure != measure {
             // Add another event that 'snapshots' the current BPM and time, so we can show how long
@@ -105,6 +125,7 @@ impl Timeline {
                 pos,
                 bpm,
                 length,
+                scroll,
             });
         }
         // Now we can add the STOP event, which has the same measure, bpm, and length as the last
@@ -116,6 +137,7 @@ impl Timeline {
             pos,
             bpm,
             length,
+            scroll,
         });
     }
 
@@ -143,7 +165,8 @@ impl Timeline {
             self.events[i].pos = self.events[i - 1].pos
                 + ((self.events[i].measure - self.events[i - 1].measure)
                     * self.events[i - 1].bpm
-                    * self.events[i - 1].length);
+                    * self.events[i - 1].length
+                    * self.events[i - 1].scroll);
         }
     }
 
@@ -154,10 +177,30 @@ impl Timeline {
         let event_block = &self.events[event_index];
         let mut pos = event_block.pos * speed;
         // Adding the last little bit of position
-        pos += ((measure - event_block.measure) * speed * event_block.bpm) * event_block.length;
+        pos += ((measure - event_block.measure) * speed * event_block.bpm)
+            * event_block.length
+            * event_block.scroll;
         pos
     }
 
+    /// Returns the note speed multiplier at `time`, to be passed on to `pos_from_measure`.
+    /// The multiplier is 1 until the first keyframe, is interpolated linearly between
+    /// keyframes, and keeps the value of the last keyframe after it.
+    pub fn speed_at(&self, time: i64) -> f32 {
+        let next = self.speeds.partition_point(|k| k.time <= time);
+        if next == 0 {
+            return 1_f32;
+        }
+        let prev = &self.speeds[next - 1];
+        match self.speeds.get(next) {
+            Some(next) => {
+                let progress = (time - prev.time) as f32 / (next.time - prev.time) as f32;
+                prev.speed + (next.speed - prev.speed) * progress
+            }
+            None => prev.speed,
+        }
+    }
+
     /// Convert a time position to a measure value
     pub fn measure_from_time(&self, time: i64) -> f32 {
         // If we can guarantee sortedness of events, this should be fine
@@ -233,20 +276,64 @@ impl Timeline {
     }
 }
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+#[derive(Clone, Copy, Debug)]
 pub enum TimelineEvent {
-    BPM { measure: f32, bpm: f32 },
-    STOP { measure: f32, duration: f32 },
+    BPM {
+        measure: f32,
+        bpm: f32,
+    },
+    STOP {
+        measure: f32,
+        duration: f32,
+    },
+    /// A change of the scroll speed factor, which only affects positions.
+    Scroll {
+        measure: f32,
+        factor: f32,
+    },
+    /// A keyframe of the interpolated note speed multiplier.
+    Speed {
+        measure: f32,
+        speed: f32,
+    },
+}
+
+/// The timing definitions a timeline is built from, kept so the chart can be written back out.
+#[derive(Debug, Default)]
+pub struct TimingDefinitions {
+    /// The `#BPM` header, or the default of 130.
+    pub base_bpm: f32,
+    /// The `#BPMxx`/`#EXBPMxx` definitions.
+    pub bpms: HashMap<Alphanumeric, f32>,
+    /// The `#STOPxx` definitions, in 192nds of a measure.
+    pub stops: HashMap<Alphanumeric, f32>,
+    /// The `#SCROLLxx` definitions.
+    pub scrolls: HashMap<Alphanumeric, f32>,
+    /// The `#SPEEDxx` definitions.
+    pub speeds: HashMap<Alphanumeric, f32>,
+    /// The channel 02 length of every measure that isn't 1.
+    pub measure_lengths: BTreeMap<u32, f32>,
+    /// The BPM changes, STOPs, scroll and speed changes, in the order they were read.
+    pub events: Vec<TimelineEvent>,
 }
 
 pub struct TimelineBuilder {
     // Data for building the timeline
-    base_bpm: f32,                     // #BPM XX
-    bpms: HashMap<Alphanumeric, f32>,  // BPM mapping (only applies to channel 8)
-    stops: HashMap<Alphanumeric, f32>, // STOP mapping
-    measure_lens: Vec<f32>,            // Measure lengths
-    events: Vec<TimelineEvent>,        // Collection of all events in the timeline
+    base_bpm: f32,                       // #BPM XX
+    bpms: HashMap<Alphanumeric, f32>,    // BPM mapping (only applies to channel 8)
+    stops: HashMap<Alphanumeric, f32>,   // STOP mapping
+    scrolls: HashMap<Alphanumeric, f32>, // SCROLL mapping
+    speeds: HashMap<Alphanumeric, f32>,  // SPEED mapping
+    measure_lens: Vec<f32>,              // Measure lengths
+    events: Vec<TimelineEvent>,          // Collection of all events in the timeline
+}
+
+impl Default for TimelineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TimelineBuilder {
@@ -255,6 +342,8 @@ impl TimelineBuilder {
             base_bpm: 130_f32, // 130 BPM is the default according to the specifications
             bpms: HashMap::new(),
             stops: HashMap::new(),
+            scrolls: HashMap::new(),
+            speeds: HashMap::new(),
             measure_lens: vec![1.0; 1000],
             events: Vec::new(),
         }
@@ -268,6 +357,9 @@ impl TimelineBuilder {
         // We'll also separate the BPM events from the STOP events, which will make building easier
         let mut bpm_measures: Vec<(f32, f32)> = Vec::new();
         let mut stop_measures: Vec<(f32, f32)> = Vec::new();
+        let mut scroll_measures: Vec<(f32, f32)> = Vec::new();
+        // Speed keyframes don't change the timeline events, and are only placed in time later on
+        let mut speed_measures: Vec<(f32, f32)> = Vec::new();
 
         for event in &self.events {
             match event {
@@ -282,6 +374,16 @@ impl TimelineBuilder {
                     stop_measures.push((*m, *d));
                     timeline_measures.push(*m);
                 }
+                TimelineEvent::Scroll {
+                    measure: m,
+                    factor: f,
+                } => {
+                    scroll_measures.push((*m, *f));
+                    timeline_measures.push(*m);
+                }
+                TimelineEvent::Speed { measure: m, speed } => {
+                    speed_measures.push((*m, *speed));
+                }
             }
         }
 
@@ -308,9 +410,10 @@ impl TimelineBuilder {
         // Sort BPM and STOP measures as well
         bpm_measures.sort_by(|(a1, _a2), (b1, _b2)| a1.partial_cmp(b1).unwrap());
         stop_measures.sort_by(|(a1, _a2), (b1, _b2)| a1.partial_cmp(b1).unwrap());
+        scroll_measures.sort_by(|(a1, _a2), (b1, _b2)| a1.partial_cmp(b1).unwrap());
 
         // Begin creating a Timeline of Events
-        let mut timeline = Timeline { events: Vec::new() };
+        let mut timeline = Timeline::default();
         // Memoizing the last event to occur in each category
         // last_bpm is equal to the base BPM if there are no BPM changes, or the first BPM change does not occur at measure 0.
         let mut last_bpm: f32 = if bpm_measures.is_empty()
@@ -322,6 +425,8 @@ impl TimelineBuilder {
             bpm_measures[0].1
         };
         let mut last_len: f32 = measure_indices[0].1;
+        // Scrolling is at normal speed until the first scroll change
+        let mut last_scroll: f32 = 1_f32;
         // Add the first event in timeline
         // timeline.add_event(0_f32, last_bpm, last_len);
 
@@ -335,25 +440,42 @@ impl TimelineBuilder {
                 last_bpm = bpm_measures[index].1;
             }
             // Update measure length if applicable
-            if (measure.floor() - measure).abs() <= ::std::f32::EPSILON {
+            if (measure.floor() - measure).abs() <= f32::EPSILON {
                 if let Ok(index) =
                     measure_indices.binary_search_by(|(a, _b)| a.cmp(&(measure.floor() as u16)))
                 {
                     last_len = measure_indices[index].1;
                 }
             }
+            // Update scroll factor if applicable
+            if let Ok(index) =
+                scroll_measures.binary_search_by(|(a, _b)| a.partial_cmp(&measure).unwrap())
+            {
+                last_scroll = scroll_measures[index].1;
+            }
             // Add stop if applicable
             if let Ok(index) =
                 stop_measures.binary_search_by(|(a, _b)| a.partial_cmp(&measure).unwrap())
             {
                 timeline.add_stop_event(measure, stop_measures[index].1);
             }
-            timeline.add_event(measure, last_bpm, last_len);
+            timeline.add_event(measure, last_bpm, last_len, last_scroll);
         }
 
         // Finally, cache the positions of events
         timeline.cache_event_pos();
 
+        // Place speed keyframes in time, now that the timeline is complete
+        speed_measures.sort_by(|(a1, _a2), (b1, _b2)| a1.partial_cmp(b1).unwrap());
+        timeline.speeds = speed_measures
+            .into_iter()
+            .map(|(measure, speed)| SpeedKeyframe {
+                time: timeline.time_from_measure(measure),
+                measure,
+                speed,
+            })
+            .collect();
+
         timeline
     }
 
@@ -363,6 +485,11 @@ impl TimelineBuilder {
         self
     }
 
+    /// Returns the bpm set by `#BPM`, or the default of 130.
+    pub fn base_bpm(&self) -> f32 {
+        self.base_bpm
+    }
+
     pub fn with_bpm(&mut self, bpm_key: Alphanumeric, bpm: f32) -> &Self {
         self.bpms.insert(bpm_key, bpm);
         self
@@ -373,6 +500,16 @@ impl TimelineBuilder {
         self
     }
 
+    pub fn with_scroll(&mut self, scroll_key: Alphanumeric, scroll: f32) -> &Self {
+        self.scrolls.insert(scroll_key, scroll);
+        self
+    }
+
+    pub fn with_speed(&mut self, speed_key: Alphanumeric, speed: f32) -> &Self {
+        self.speeds.insert(speed_key, speed);
+        self
+    }
+
     /// Adds a timeline event to the builder.
     pub fn with_event(&mut self, event: TimelineEvent) -> &Self {
         self.events.push(event);
@@ -384,13 +521,42 @@ impl TimelineBuilder {
         self
     }
 
+    /// Consumes the builder, returning the definitions it holds.
+    pub fn into_definitions(self) -> TimingDefinitions {
+        TimingDefinitions {
+            base_bpm: self.base_bpm,
+            bpms: self.bpms,
+            stops: self.stops,
+            scrolls: self.scrolls,
+            speeds: self.speeds,
+            measure_lengths: self
+                .measure_lens
+                .iter()
+                .enumerate()
+                .filter(|(_, &length)| length != 1_f32)
+                .map(|(measure, &length)| (measure as u32, length))
+                .collect(),
+            events: self.events,
+        }
+    }
+
     /// Finds a previously-inserted bpm value with the given key
-    pub fn find_bpm(&self, bpm_key: Alphanumeric) -> f32 {
-        *self.bpms.get(&bpm_key).unwrap_or(&0_f32)
+    pub fn find_bpm(&self, bpm_key: &Alphanumeric) -> Option<f32> {
+        self.bpms.get(bpm_key).copied()
     }
 
     /// Finds a previously-inserted stop value with the given key
-    pub fn find_stop(&self, stop_key: Alphanumeric) -> f32 {
-        *self.stops.get(&stop_key).unwrap_or(&0_f32)
+    pub fn find_stop(&self, stop_key: &Alphanumeric) -> Option<f32> {
+        self.stops.get(stop_key).copied()
+    }
+
+    /// Finds a previously-inserted scroll factor with the given key
+    pub fn find_scroll(&self, scroll_key: &Alphanumeric) -> Option<f32> {
+        self.scrolls.get(scroll_key).copied()
+    }
+
+    /// Finds a previously-inserted speed multiplier with the given key
+    pub fn find_speed(&self, speed_key: &Alphanumeric) -> Option<f32> {
+        self.speeds.get(speed_key).copied()
     }
 }
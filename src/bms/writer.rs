@@ -0,0 +1,476 @@
+/// Serialization of a `BMS` chart back into BMS text.
+///
+/// Every channel line is written with the coarsest grid that places all of its objects exactly
+/// where they were, so parsing the written text gives back the same chart.
+use crate::bms::{
+    charset::BmsEncoding, error::WriteError, format::BMS, parser::METADATA_HEADERS,
+    timeline::TimelineEvent, Alphanumeric, Base, ObjType,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+/// The finest grid a channel line is written with. Positions that don't fall on any grid up to
+/// this resolution are moved to the nearest slot.
+const MAX_RESOLUTION: usize = 3840;
+
+/// The SC (scroll) and SP (speed) channels, as base-36 numbers.
+const SCROLL_CHANNEL: u32 = 28 * 36 + 12;
+const SPEED_CHANNEL: u32 = 28 * 36 + 25;
+
+/// A value placed on a channel line.
+struct Slot {
+    measure: f32,
+    value: String,
+}
+
+/// The part of an `#LNTYPE 2` long note that falls in one measure. The note's key fills every
+/// slot it is held for.
+struct HeldNote {
+    start: f32,
+    end: f32,
+    key: String,
+}
+
+/// The channel lines of a chart, by measure and channel. Objects that share a position are
+/// placed on separate lines for the same channel.
+#[derive(Default)]
+struct ChannelLines {
+    lines: BTreeMap<(u32, u32), Vec<Vec<Slot>>>,
+}
+
+impl ChannelLines {
+    fn place(&mut self, channel: u32, measure: f32, value: String) {
+        let layers = self
+            .lines
+            .entry((measure.floor() as u32, channel))
+            .or_default();
+        let slot = Slot { measure, value };
+        match layers
+            .iter_mut()
+            .find(|layer| layer.iter().all(|s| s.measure != measure))
+        {
+            Some(layer) => layer.push(slot),
+            None => layers.push(vec![slot]),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BmsWriter {
+    // Encoding forced by the caller; the chart's own encoding is used if not set.
+    encoding: Option<BmsEncoding>,
+}
+
+impl BmsWriter {
+    pub fn new() -> BmsWriter {
+        BmsWriter { encoding: None }
+    }
+
+    /// Writes charts in the given encoding instead of the one they were read with.
+    pub fn with_encoding(&mut self, encoding: BmsEncoding) -> &Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Writes the chart as BMS text and encodes it. The chart's own encoding is used unless
+    /// one was forced, and UTF-8 is used instead if the text can't be represented in it.
+    /// Returns the encoded text along with the encoding that was used.
+    pub fn write_bytes(&self, bms: &BMS) -> Result<(Vec<u8>, BmsEncoding), WriteError> {
+        let text = self.write(bms)?;
+        let encoding = self.encoding.unwrap_or(bms.encoding);
+        Ok(match encoding.try_encode(&text) {
+            Some(bytes) => (bytes, encoding),
+            None => (text.into_bytes(), BmsEncoding::Utf8),
+        })
+    }
+
+    /// Writes the chart as BMS text. Fails if the chart holds a key that doesn't fit in two
+    /// digits of its base, which only charts built by hand or converted from bmson can.
+    pub fn write(&self, bms: &BMS) -> Result<String, WriteError> {
+        let base = bms.base;
+        let timing = &bms.timing;
+        let mut lines = ChannelLines::default();
+        let mut bpms = timing.bpms.clone();
+        let mut stops = timing.stops.clone();
+        let mut scrolls = timing.scrolls.clone();
+        let mut speeds = timing.speeds.clone();
+        let mut lnobjs = bms.lnobjs.clone();
+        let long_note_type2 = bms.header.long_note_type() == 2;
+        let mut held: BTreeMap<(u32, u32), Vec<HeldNote>> = BTreeMap::new();
+
+        let mut events = timing.events.clone();
+        events.sort_by(|e1, e2| event_measure(e1).partial_cmp(&event_measure(e2)).unwrap());
+        for event in events {
+            match event {
+                TimelineEvent::BPM { measure, bpm } => {
+                    if bpm.fract() == 0_f32 && (1_f32..=255_f32).contains(&bpm) {
+                        lines.place(3, measure, format!("{:02X}", bpm as u8));
+                    } else if let Some(key) = define(&mut bpms, bpm, base) {
+                        lines.place(8, measure, format_key(key, base)?);
+                    }
+                }
+                TimelineEvent::STOP { measure, duration } => {
+                    if let Some(key) = define(&mut stops, duration, base) {
+                        lines.place(9, measure, format_key(key, base)?);
+                    }
+                }
+                TimelineEvent::Scroll { measure, factor } => {
+                    if let Some(key) = define(&mut scrolls, factor, base) {
+                        lines.place(SCROLL_CHANNEL, measure, format_key(key, base)?);
+                    }
+                }
+                TimelineEvent::Speed { measure, speed } => {
+                    if let Some(key) = define(&mut speeds, speed, base) {
+                        lines.place(SPEED_CHANNEL, measure, format_key(key, base)?);
+                    }
+                }
+            }
+        }
+
+        for object in bms.objects.iter() {
+            let channel = object.channel;
+            match object.objtype {
+                ObjType::Auto(key)
+                | ObjType::Note(key)
+                | ObjType::Invisible(key)
+                | ObjType::BGA { key, .. }
+                | ObjType::BGAArgb { key, .. } => {
+                    lines.place(channel, object.measure, format_key(key, base)?);
+                }
+                ObjType::BGAOpacity { opacity, .. } => {
+                    lines.place(channel, object.measure, format!("{:02X}", opacity));
+                }
+                ObjType::Mine { damage } => {
+                    let damage = format_key(Alphanumeric::from_int(damage as usize), Base::Base36)?;
+                    lines.place(channel, object.measure, damage);
+                }
+                ObjType::LongNote {
+                    key, end_measure, ..
+                } => {
+                    let (start, key) = (object.measure, format_key(key, base)?);
+                    if !(5..=6).contains(&(channel / 36)) {
+                        // A note ended by an #LNOBJ marker
+                        if lnobjs.is_empty() {
+                            lnobjs.extend(unused_key(bms));
+                        }
+                        if let Some(lnobj) = lnobjs.first() {
+                            lines.place(channel, start, key);
+                            lines.place(channel, end_measure, format_key(*lnobj, base)?);
+                        }
+                    } else if long_note_type2 {
+                        let mut measure = start.floor();
+                        while measure < end_measure {
+                            held.entry((measure as u32, channel))
+                                .or_default()
+                                .push(HeldNote {
+                                    start: start.max(measure),
+                                    end: end_measure.min(measure + 1_f32),
+                                    key: key.clone(),
+                                });
+                            measure += 1_f32;
+                        }
+                    } else {
+                        lines.place(channel, start, key.clone());
+                        lines.place(channel, end_measure, key);
+                    }
+                }
+            }
+        }
+
+        let mut out = String::new();
+        if base == Base::Base62 {
+            out.push_str("#BASE 62\n");
+        }
+        let raw = &bms.header.raw;
+        let mut other_headers: Vec<&String> = raw
+            .keys()
+            .filter(|header| !METADATA_HEADERS.contains(&header.as_str()))
+            .collect();
+        other_headers.sort();
+        for header in METADATA_HEADERS
+            .iter()
+            .copied()
+            .chain(other_headers.iter().map(|header| header.as_str()))
+        {
+            match raw.get(header).map(String::as_str) {
+                Some("") => writeln!(out, "#{}", header).unwrap(),
+                Some(value) => writeln!(out, "#{} {}", header, value).unwrap(),
+                None => {}
+            }
+        }
+        writeln!(out, "#BPM {}", timing.base_bpm).unwrap();
+        for lnobj in lnobjs.iter() {
+            writeln!(out, "#LNOBJ {}", format_key(*lnobj, base)?).unwrap();
+        }
+
+        write_definitions(&mut out, "WAV", &bms.keysounds, base)?;
+        write_definitions(&mut out, "BMP", &bms.bga_layers, base)?;
+        write_definitions(&mut out, "BPM", &bpms, base)?;
+        write_definitions(&mut out, "STOP", &stops, base)?;
+        write_definitions(&mut out, "SCROLL", &scrolls, base)?;
+        write_definitions(&mut out, "SPEED", &speeds, base)?;
+        write_definitions(&mut out, "EXRANK", &bms.exranks, base)?;
+        write_definitions(&mut out, "ARGB", &bms.argbs, base)?;
+
+        out.push('\n');
+        for (measure, length) in timing.measure_lengths.iter() {
+            writeln!(out, "#{:03}02:{}", measure, length).unwrap();
+        }
+        for (&(measure, channel), layers) in lines.lines.iter() {
+            for layer in layers {
+                let resolution = resolution(measure, layer.iter().map(|slot| slot.measure));
+                let mut data = vec!["00"; resolution];
+                for slot in layer {
+                    data[slot_index(measure, slot.measure, resolution)] = &slot.value;
+                }
+                write_line(&mut out, measure, channel, &data);
+            }
+        }
+        for (&(measure, channel), notes) in held.iter() {
+            // The slot where a note is released stays empty, unless another note starts there
+            let boundaries = notes.iter().flat_map(|note| [note.start, note.end]);
+            let resolution = resolution(
+                measure,
+                boundaries.filter(|&position| position < measure as f32 + 1_f32),
+            );
+            let mut data = vec!["00"; resolution];
+            for note in notes {
+                let end = if note.end < measure as f32 + 1_f32 {
+                    slot_index(measure, note.end, resolution)
+                } else {
+                    resolution
+                };
+                for slot in &mut data[slot_index(measure, note.start, resolution)..end] {
+                    *slot = &note.key;
+                }
+            }
+            write_line(&mut out, measure, channel, &data);
+        }
+        Ok(out)
+    }
+}
+
+fn event_measure(event: &TimelineEvent) -> f32 {
+    match *event {
+        TimelineEvent::BPM { measure, .. }
+        | TimelineEvent::STOP { measure, .. }
+        | TimelineEvent::Scroll { measure, .. }
+        | TimelineEvent::Speed { measure, .. } => measure,
+    }
+}
+
+/// Returns the number of keys that can be written in the base.
+fn key_count(base: Base) -> usize {
+    match base {
+        Base::Base36 => 36 * 36,
+        Base::Base62 => 62 * 62,
+    }
+}
+
+/// Finds the key defined with the value, or defines the value with the first free key. Returns
+/// None if every key is taken.
+fn define(
+    definitions: &mut HashMap<Alphanumeric, f32>,
+    value: f32,
+    base: Base,
+) -> Option<Alphanumeric> {
+    let existing = definitions
+        .iter()
+        .filter(|(_, &v)| v == value)
+        .map(|(&key, _)| key)
+        .min_by_key(|key| key.key);
+    existing.or_else(|| {
+        let key = (1..key_count(base))
+            .map(Alphanumeric::from_int)
+            .find(|key| !definitions.contains_key(key))?;
+        definitions.insert(key, value);
+        Some(key)
+    })
+}
+
+/// Finds a key that no object or keysound uses, to mark the end of `#LNOBJ` long notes.
+fn unused_key(bms: &BMS) -> Option<Alphanumeric> {
+    let used: Vec<usize> = bms
+        .objects
+        .iter()
+        .filter_map(|object| match object.objtype {
+            ObjType::Auto(key)
+            | ObjType::Note(key)
+            | ObjType::Invisible(key)
+            | ObjType::LongNote { key, .. } => Some(key.key),
+            _ => None,
+        })
+        .collect();
+    (1..key_count(bms.base))
+        .rev()
+        .map(Alphanumeric::from_int)
+        .find(|key| !used.contains(&key.key) && !bms.keysounds.contains_key(key))
+}
+
+/// Formats a key with two digits in the base.
+fn format_key(key: Alphanumeric, base: Base) -> Result<String, WriteError> {
+    if key.fits(base) {
+        Ok(key.as_base(base))
+    } else {
+        Err(WriteError::KeyOutOfRange(key))
+    }
+}
+
+fn write_definitions<V: std::fmt::Display>(
+    out: &mut String,
+    command: &str,
+    definitions: &HashMap<Alphanumeric, V>,
+    base: Base,
+) -> Result<(), WriteError> {
+    let mut definitions: Vec<(&Alphanumeric, &V)> = definitions.iter().collect();
+    definitions.sort_by_key(|(key, _)| key.key);
+    for (key, value) in definitions {
+        writeln!(out, "#{}{} {}", command, format_key(*key, base)?, value).unwrap();
+    }
+    Ok(())
+}
+
+fn write_line(out: &mut String, measure: u32, channel: u32, data: &[&str]) {
+    let channel = Alphanumeric::from_int(channel as usize).as_base36();
+    writeln!(out, "#{:03}{}:{}", measure, channel, data.concat()).unwrap();
+}
+
+/// Returns the position of a slot the same way the parser computes it, so positions can be
+/// compared exactly.
+fn slot_measure(measure: u32, slot: usize, resolution: usize) -> f32 {
+    measure as f32 + (slot * 2) as f32 / (resolution * 2) as f32
+}
+
+/// Returns the slot nearest to the position on a grid of the given resolution.
+fn slot_index(measure: u32, position: f32, resolution: usize) -> usize {
+    let slot = ((position - measure as f32) * resolution as f32).round() as usize;
+    slot.min(resolution - 1)
+}
+
+/// Finds the smallest resolution whose slots land exactly on every position.
+fn resolution(measure: u32, positions: impl Iterator<Item = f32> + Clone) -> usize {
+    (1..=MAX_RESOLUTION)
+        .find(|&resolution| {
+            positions.clone().all(|position| {
+                let slot = slot_index(measure, position, resolution);
+                slot_measure(measure, slot, resolution) == position
+            })
+        })
+        .unwrap_or(MAX_RESOLUTION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bms::{parser::BmsParser, random::FixedChoices};
+
+    fn parse(contents: &[u8]) -> BMS {
+        BmsParser::new()
+            .parse_with_rng(contents, &mut FixedChoices::default())
+            .unwrap()
+    }
+
+    /// Writes the chart, parses it again and checks that nothing changed.
+    fn assert_round_trip(bms: &BMS) -> String {
+        let (bytes, encoding) = BmsWriter::new().write_bytes(bms).unwrap();
+        let written = parse(&bytes);
+        assert!(written.warnings.is_empty(), "{:?}", written.warnings);
+        assert_eq!(encoding, bms.encoding);
+        assert_eq!(written.encoding, bms.encoding);
+        assert_eq!(written.base, bms.base);
+        assert_eq!(written.header, bms.header);
+        assert_eq!(written.keysounds, bms.keysounds);
+        assert_eq!(written.bga_layers, bms.bga_layers);
+        assert_eq!(written.exranks, bms.exranks);
+        assert_eq!(written.argbs, bms.argbs);
+        assert_eq!(written.timing.base_bpm, bms.timing.base_bpm);
+        assert_eq!(written.timing.bpms, bms.timing.bpms);
+        assert_eq!(written.timing.stops, bms.timing.stops);
+        assert_eq!(written.timing.scrolls, bms.timing.scrolls);
+        assert_eq!(written.timing.speeds, bms.timing.speeds);
+        assert_eq!(written.timing.measure_lengths, bms.timing.measure_lengths);
+        assert_eq!(
+            format!("{:?}", written.timeline),
+            format!("{:?}", bms.timeline)
+        );
+        assert_eq!(
+            format!("{:?}", written.objects),
+            format!("{:?}", bms.objects)
+        );
+        BmsWriter::new().write(bms).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bms = parse(
+            b"#PLAYER 1\n#TITLE Round Trip\n#ARTIST someone\n#PLAYLEVEL 12\n#RANK 2\n\
+              #TOTAL 250.5\n#COMMENT \"a comment\"\n#BPM 150\n#EXBPM01 187.5\n#STOP01 48\n\
+              #SCROLL01 0.5\n#SPEED01 2\n#EXRANK01 48\n#WAV01 kick.wav\n#WAV02 snare.wav\n\
+              #BMP01 bg.png\n#ARGB01 128,255,0,0\n#00002:0.75\n#00101:01020102\n#00101:0001\n\
+              #00111:010000000000000000000000000000020000000000000000000000000000000000000000\n\
+              #00116:0000000000000000000000000000000000000000000000000000000000000001\n\
+              #00103:0000B400\n#00108:01\n#00109:0001\n#001SC:01\n#002SP:0001\n#00204:01\n\
+              #0020B:80\n#0020H:01\n#002D1:0Z\n#00231:02\n#00351:0100\n#00451:0001\n",
+        );
+        assert_round_trip(&bms);
+    }
+
+    #[test]
+    fn test_minimal_resolution() {
+        let bms = parse(b"#WAV01 a.wav\n#00111:0100000001000000\n#00112:000000000000000001\n");
+        let written = assert_round_trip(&bms);
+        assert!(written.contains("#00111:0101\n"));
+        assert!(written.contains("#00112:000000000000000001\n"));
+    }
+
+    #[test]
+    fn test_round_trip_long_notes() {
+        assert_round_trip(&parse(
+            b"#LNOBJ ZZ\n#00111:0100ZZ00\n#00112:01\n#00212:00ZZ\n",
+        ));
+        let written = assert_round_trip(&parse(
+            b"#LNTYPE 2\n#00151:00010101\n#00251:0100\n#00152:0202\n#00352:0200\n",
+        ));
+        assert!(written.contains("#00151:00010101\n"));
+        let written = assert_round_trip(&parse(b"#LNTYPE 02\n#00151:00010101\n#00251:0100\n"));
+        assert!(written.contains("#00151:00010101\n"));
+    }
+
+    #[test]
+    fn test_round_trip_base62() {
+        assert_round_trip(&parse(
+            b"#BASE 62\n#WAVab a.wav\n#WAVAB b.wav\n#BPMzz 1.5\n#00111:abAB\n#00108:zz\n",
+        ));
+    }
+
+    #[test]
+    fn test_encoding() {
+        // "#TITLE テスト" in Shift_JIS
+        let bms = parse(b"#TITLE \x83\x65\x83\x58\x83\x67\n");
+        assert_eq!(bms.encoding, BmsEncoding::ShiftJis);
+        assert_round_trip(&bms);
+
+        // Hangul can't be written in Shift_JIS
+        let mut writer = BmsWriter::new();
+        writer.with_encoding(BmsEncoding::ShiftJis);
+        let bms = parse("#TITLE 한국\n".as_bytes());
+        let (bytes, encoding) = writer.write_bytes(&bms).unwrap();
+        assert_eq!(encoding, BmsEncoding::Utf8);
+        assert_eq!(parse(&bytes).header.title.as_deref(), Some("한국"));
+    }
+
+    #[test]
+    fn test_key_out_of_range() {
+        let mut bms = parse(b"#WAV01 a.wav\n#00111:01\n");
+        bms.keysounds
+            .insert(Alphanumeric::from_int(2000), "b.wav".to_string());
+        assert_eq!(
+            BmsWriter::new().write(&bms),
+            Err(WriteError::KeyOutOfRange(Alphanumeric::from_int(2000)))
+        );
+        bms.base = Base::Base62;
+        assert!(BmsWriter::new().write(&bms).is_ok());
+    }
+}
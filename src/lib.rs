@@ -0,0 +1,3 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod bms;
@@ -1,7 +1,5 @@
-mod bms;
-
-use bms::parser::BmsParser;
-use std::{env, fs::File};
+use bms_rs::bms::parser::BmsParser;
+use std::{env, fs::File, process};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -10,9 +8,16 @@ fn main() {
         return;
     }
 
-    let mut f = File::open(&args[1]).expect("File not found.");
-    let bp = BmsParser;
-    let bms = bp.parse(&mut f);
+    let f = File::open(&args[1]).expect("File not found.");
+    let mut bp = BmsParser::new();
+    bp.with_path(&args[1]);
+    let bms = match bp.parse_reader(f) {
+        Ok(bms) => bms,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
 
     println!("{:#?}", bms);
 }